@@ -0,0 +1,331 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use bevy::math::Vec3;
+use bevy::prelude::Mesh;
+use bevy::render::mesh::VertexAttributeValues;
+use serde_json::json;
+use thiserror::Error;
+use crate::ATTRIBUTE_TEXTURE_INDEX;
+use crate::hexgrid::HexGrid;
+use crate::terrain::TerrainRegistry;
+
+#[derive(Debug, Error)]
+pub enum MeshExportError {
+    #[error("could not write mesh: {0}")]
+    Io(#[from] io::Error),
+    #[error("mesh has no {0} attribute in the format the exporter expects")]
+    MissingAttribute(&'static str),
+    #[error("mesh has no indices")]
+    MissingIndices,
+    #[error("mesh has no triangles")]
+    Empty,
+    #[error("terrain layer {0} doesn't fit in an unsigned short")]
+    TerrainLayerOutOfRange(u32),
+}
+
+//what goes in the vertex colors of an OBJ file, which only has one color per vertex
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ObjColor {
+    //the mesh's vertex colors, which are the weights of the three terrain layers
+    #[default]
+    Blend,
+    //the three terrain layers, each divided by 255
+    TerrainIndex,
+}
+
+//the attributes triangulate_grid makes
+struct MeshArrays<'a> {
+    positions: &'a [[f32; 3]],
+    normals: &'a [[f32; 3]],
+    uvs: &'a [[f32; 2]],
+    colors: &'a [[f32; 4]],
+    terrain: &'a [[u32; 3]],
+    indices: Vec<u32>,
+}
+
+impl<'a> MeshArrays<'a> {
+    fn new(mesh: &'a Mesh) -> Result<MeshArrays<'a>, MeshExportError> {
+        let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+            Some(VertexAttributeValues::Float32x3(v)) => v,
+            _ => return Err(MeshExportError::MissingAttribute("position")),
+        };
+        let normals = match mesh.attribute(Mesh::ATTRIBUTE_NORMAL) {
+            Some(VertexAttributeValues::Float32x3(v)) => v,
+            _ => return Err(MeshExportError::MissingAttribute("normal")),
+        };
+        let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+            Some(VertexAttributeValues::Float32x2(v)) => v,
+            _ => return Err(MeshExportError::MissingAttribute("uv")),
+        };
+        let colors = match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+            Some(VertexAttributeValues::Float32x4(v)) => v,
+            _ => return Err(MeshExportError::MissingAttribute("color")),
+        };
+        let terrain = match mesh.attribute(ATTRIBUTE_TEXTURE_INDEX) {
+            Some(VertexAttributeValues::Uint32x3(v)) => v,
+            _ => return Err(MeshExportError::MissingAttribute("texture index")),
+        };
+        let indices = mesh.indices()
+            .ok_or(MeshExportError::MissingIndices)?
+            .iter()
+            .map(|i| i as u32)
+            .collect();
+        Ok(MeshArrays{positions, normals, uvs, colors, terrain, indices})
+    }
+}
+
+//binary glTF 2.0 (.glb) of a mesh made by HexGrid::triangulate_grid.
+//the terrain layers are in the custom attribute _TERRAIN_INDEX as unsigned shorts, and in COLOR_1
+//divided by 255 for tools that only import color layers. glTF has no empty buffers, so a mesh
+//without triangles is an error.
+pub fn mesh_to_glb(mesh: &Mesh) -> Result<Vec<u8>, MeshExportError> {
+    const ARRAY_BUFFER: u32 = 34962;
+    const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+    const FLOAT: u32 = 5126;
+    const UNSIGNED_SHORT: u32 = 5123;
+    const UNSIGNED_INT: u32 = 5125;
+
+    let arrays = MeshArrays::new(mesh)?;
+    let count = arrays.positions.len();
+    if count == 0 || arrays.indices.is_empty() {
+        return Err(MeshExportError::Empty);
+    }
+    let mut bin = Vec::new();
+    let mut views = Vec::new();
+    let mut accessors = Vec::new();
+    //every view starts 4 byte aligned, which every element is then too
+    let mut add_view = |bin: &mut Vec<u8>, bytes: Vec<u8>, stride: Option<usize>, target: u32| {
+        let offset = bin.len();
+        bin.extend_from_slice(&bytes);
+        bin.resize(bin.len().next_multiple_of(4), 0);
+        let mut view = json!({"buffer": 0, "byteOffset": offset, "byteLength": bytes.len(), "target": target});
+        if let Some(stride) = stride {
+            view["byteStride"] = json!(stride);
+        }
+        views.push(view);
+        views.len() - 1
+    };
+    let floats = |values: &mut dyn Iterator<Item = f32>| values.flat_map(f32::to_le_bytes).collect::<Vec<u8>>();
+
+    let (min, max) = arrays.positions.iter()
+        .fold((Vec3::MAX, Vec3::MIN), |(min, max), &p| (min.min(p.into()), max.max(p.into())));
+
+    let view = add_view(&mut bin, floats(&mut arrays.positions.iter().flatten().copied()), None, ARRAY_BUFFER);
+    accessors.push(json!({"bufferView": view, "componentType": FLOAT, "count": count, "type": "VEC3",
+        "min": min.to_array(), "max": max.to_array()}));
+    let view = add_view(&mut bin, floats(&mut arrays.normals.iter().flatten().copied()), None, ARRAY_BUFFER);
+    accessors.push(json!({"bufferView": view, "componentType": FLOAT, "count": count, "type": "VEC3"}));
+    let view = add_view(&mut bin, floats(&mut arrays.uvs.iter().flatten().copied()), None, ARRAY_BUFFER);
+    accessors.push(json!({"bufferView": view, "componentType": FLOAT, "count": count, "type": "VEC2"}));
+    let view = add_view(&mut bin, floats(&mut arrays.colors.iter().flatten().copied()), None, ARRAY_BUFFER);
+    accessors.push(json!({"bufferView": view, "componentType": FLOAT, "count": count, "type": "VEC4"}));
+    //padded to 8 bytes, vertex attribute elements have to be 4 byte aligned
+    let terrain = arrays.terrain.iter()
+        .flat_map(|t| [t[0], t[1], t[2], 0])
+        .map(|t| u16::try_from(t).map_err(|_| MeshExportError::TerrainLayerOutOfRange(t)))
+        .collect::<Result<Vec<u16>, MeshExportError>>()?
+        .into_iter()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    let view = add_view(&mut bin, terrain, Some(8), ARRAY_BUFFER);
+    accessors.push(json!({"bufferView": view, "componentType": UNSIGNED_SHORT, "count": count, "type": "VEC3"}));
+    let terrain_colors = &mut arrays.terrain.iter()
+        .flat_map(|t| [t[0] as f32/255.0, t[1] as f32/255.0, t[2] as f32/255.0, 1.0]);
+    let view = add_view(&mut bin, floats(terrain_colors), None, ARRAY_BUFFER);
+    accessors.push(json!({"bufferView": view, "componentType": FLOAT, "count": count, "type": "VEC4"}));
+    let indices = arrays.indices.iter().flat_map(|i| i.to_le_bytes()).collect();
+    let view = add_view(&mut bin, indices, None, ELEMENT_ARRAY_BUFFER);
+    accessors.push(json!({"bufferView": view, "componentType": UNSIGNED_INT, "count": arrays.indices.len(), "type": "SCALAR"}));
+
+    let document = json!({
+        "asset": {"version": "2.0", "generator": "bevy_hex"},
+        "scene": 0,
+        "scenes": [{"nodes": [0]}],
+        "nodes": [{"mesh": 0, "name": "HexGrid"}],
+        "meshes": [{"primitives": [{
+            "attributes": {
+                "POSITION": 0,
+                "NORMAL": 1,
+                "TEXCOORD_0": 2,
+                "COLOR_0": 3,
+                "_TERRAIN_INDEX": 4,
+                "COLOR_1": 5
+            },
+            "indices": 6,
+            "mode": 4
+        }]}],
+        "accessors": accessors,
+        "bufferViews": views,
+        "buffers": [{"byteLength": bin.len()}]
+    });
+    let mut json = document.to_string().into_bytes();
+    json.resize(json.len().next_multiple_of(4), b' ');
+
+    let mut glb = Vec::with_capacity(28 + json.len() + bin.len());
+    glb.extend_from_slice(b"glTF");
+    glb.extend_from_slice(&2u32.to_le_bytes());
+    glb.extend_from_slice(&((28 + json.len() + bin.len()) as u32).to_le_bytes());
+    glb.extend_from_slice(&(json.len() as u32).to_le_bytes());
+    glb.extend_from_slice(b"JSON");
+    glb.extend_from_slice(&json);
+    glb.extend_from_slice(&(bin.len() as u32).to_le_bytes());
+    glb.extend_from_slice(b"BIN\0");
+    glb.extend_from_slice(&bin);
+    Ok(glb)
+}
+
+//Wavefront OBJ of a mesh made by HexGrid::triangulate_grid, with vertex colors after each position
+pub fn mesh_to_obj(mesh: &Mesh, color: ObjColor) -> Result<String, MeshExportError> {
+    let arrays = MeshArrays::new(mesh)?;
+    let mut obj = String::from("# bevy_hex\no HexGrid\n");
+    for (i, p) in arrays.positions.iter().enumerate() {
+        let c = match color {
+            ObjColor::Blend => [arrays.colors[i][0], arrays.colors[i][1], arrays.colors[i][2]],
+            ObjColor::TerrainIndex => arrays.terrain[i].map(|t| t as f32/255.0),
+        };
+        writeln!(obj, "v {} {} {} {} {} {}", p[0], p[1], p[2], c[0], c[1], c[2]).unwrap();
+    }
+    for n in arrays.normals {
+        writeln!(obj, "vn {} {} {}", n[0], n[1], n[2]).unwrap();
+    }
+    for uv in arrays.uvs {
+        writeln!(obj, "vt {} {}", uv[0], uv[1]).unwrap();
+    }
+    for triangle in arrays.indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0] + 1, triangle[1] + 1, triangle[2] + 1];
+        writeln!(obj, "f {a}/{a}/{a} {b}/{b}/{b} {c}/{c}/{c}").unwrap();
+    }
+    Ok(obj)
+}
+
+impl HexGrid {
+    pub fn save_glb(
+        &self,
+        registry: &TerrainRegistry,
+        subdivisions: u32,
+        path: impl AsRef<Path>
+    ) -> Result<(), MeshExportError> {
+        fs::write(path, mesh_to_glb(&self.triangulate_grid(registry, subdivisions))?)?;
+        Ok(())
+    }
+
+    pub fn save_obj(
+        &self,
+        registry: &TerrainRegistry,
+        subdivisions: u32,
+        color: ObjColor,
+        path: impl AsRef<Path>
+    ) -> Result<(), MeshExportError> {
+        fs::write(path, mesh_to_obj(&self.triangulate_grid(registry, subdivisions), color)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::Handle;
+    use serde_json::Value;
+    use crate::hexgrid::{HexLayout, DEFAULT_SUBDIVISIONS};
+    use crate::terrain::TerrainType;
+    use super::*;
+
+    fn registry(layers: [u32; 2]) -> TerrainRegistry {
+        TerrainRegistry {
+            texture: String::new(),
+            layers: 4,
+            types: layers.into_iter()
+                .map(|layer| TerrainType {
+                    name: layer.to_string(),
+                    layer,
+                    movement_cost: 1.0,
+                    color: [1.0; 4],
+                    tags: vec![]
+                })
+                .collect(),
+            texture_handle: Handle::default()
+        }
+    }
+
+    fn mesh() -> Mesh {
+        let mut grid = HexGrid::with_layout(5, 4, HexLayout::default());
+        grid.randomize_terrain(2, &mut HexGrid::rng(2));
+        grid.triangulate_grid(&registry([0, 1]), DEFAULT_SUBDIVISIONS)
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn glb_layout() {
+        let mesh = mesh();
+        let glb = mesh_to_glb(&mesh).unwrap();
+        assert_eq!(&glb[0..4], b"glTF");
+        assert_eq!(u32_at(&glb, 4), 2);
+        assert_eq!(u32_at(&glb, 8) as usize, glb.len());
+
+        let json_len = u32_at(&glb, 12) as usize;
+        assert_eq!(&glb[16..20], b"JSON");
+        assert_eq!(json_len%4, 0);
+        let document: Value = serde_json::from_slice(&glb[20..20 + json_len]).unwrap();
+        let bin_start = 20 + json_len;
+        let bin_len = u32_at(&glb, bin_start) as usize;
+        assert_eq!(&glb[bin_start + 4..bin_start + 8], b"BIN\0");
+        assert_eq!(bin_start + 8 + bin_len, glb.len());
+        assert_eq!(document["buffers"][0]["byteLength"], bin_len);
+
+        let vertices = mesh.count_vertices();
+        let indices = mesh.indices().unwrap().len();
+        let accessors = document["accessors"].as_array().unwrap();
+        assert_eq!(accessors.len(), 7);
+        for accessor in &accessors[..6] {
+            assert_eq!(accessor["count"], vertices);
+        }
+        assert_eq!(accessors[6]["count"], indices);
+        for view in document["bufferViews"].as_array().unwrap() {
+            let end = view["byteOffset"].as_u64().unwrap() + view["byteLength"].as_u64().unwrap();
+            assert!(end as usize <= bin_len);
+        }
+    }
+
+    #[test]
+    fn glb_errors() {
+        let empty = HexGrid::from_mask(&[], HexLayout::default());
+        let mesh = empty.triangulate_grid(&registry([0, 1]), DEFAULT_SUBDIVISIONS);
+        assert!(matches!(mesh_to_glb(&mesh), Err(MeshExportError::Empty)));
+
+        let grid = HexGrid::with_layout(2, 2, HexLayout::default());
+        let mesh = grid.triangulate_grid(&registry([70000, 1]), DEFAULT_SUBDIVISIONS);
+        assert!(matches!(mesh_to_glb(&mesh), Err(MeshExportError::TerrainLayerOutOfRange(70000))));
+    }
+
+    //faces are 1 based and point at the same position, uv and normal
+    #[test]
+    fn obj_faces() {
+        let mesh = mesh();
+        let obj = mesh_to_obj(&mesh, ObjColor::Blend).unwrap();
+        let count = |prefix: &str| obj.lines().filter(|line| line.starts_with(prefix)).count();
+        let vertices = mesh.count_vertices();
+        assert_eq!(count("v "), vertices);
+        assert_eq!(count("vn "), vertices);
+        assert_eq!(count("vt "), vertices);
+
+        let indices = mesh.indices().unwrap().iter().collect::<Vec<usize>>();
+        let faces = obj.lines()
+            .filter_map(|line| line.strip_prefix("f "))
+            .flat_map(|face| face.split(' '))
+            .map(|corner| {
+                let [v, vt, vn] = corner.split('/')
+                    .map(|i| i.parse::<usize>().unwrap())
+                    .collect::<Vec<usize>>()
+                    .try_into()
+                    .unwrap();
+                assert!(v == vt && v == vn);
+                v - 1
+            })
+            .collect::<Vec<usize>>();
+        assert_eq!(faces, indices);
+    }
+}
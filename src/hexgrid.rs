@@ -1,74 +1,441 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::{Add, Index, IndexMut, Mul, Neg, Sub};
 use bevy::asset::RenderAssetUsages;
-use bevy::math::{UVec3, Vec2, Vec3};
-use bevy::prelude::{Resource, Mesh};
+use bevy::math::{IVec2, UVec2, UVec3, Vec2, Vec2Swizzles, Vec3};
+use bevy::prelude::{Asset, Mesh, Resource, TypePath};
 use bevy::render::mesh::{Indices, PrimitiveTopology};
-use rand::random;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
 use crate::ATTRIBUTE_TEXTURE_INDEX;
+use crate::terrain::TerrainRegistry;
 
-//TODO: REPLACE WITH ENUM, SET UP THINGS TO INDEX WITH THIS
-//NE: 0
-// W: 1
-//SE: 2
-//SW: 3
-// E: 4
-//NW: 5
-static NE: usize = 0;
-static  W: usize = 1;
-static SE: usize = 2;
-static SW: usize = 3;
-static  E: usize = 4;
-static NW: usize = 5;
+//the edge between corners dir and dir+1 of a HexLayout faces dir.
+//north is the direction rows are laid out in (-z in world space), east is +x.
+//variants are named for pointy-top hexes, flat-top hexes are rotated 30 degrees counterclockwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum HexDirection {
+    NorthWest = 0,
+    West = 1,
+    SouthWest = 2,
+    SouthEast = 3,
+    East = 4,
+    NorthEast = 5,
+}
+
+impl HexDirection {
+    //counterclockwise, starting from NorthWest
+    pub const fn all() -> [HexDirection; 6] {
+        [
+            HexDirection::NorthWest,
+            HexDirection::West,
+            HexDirection::SouthWest,
+            HexDirection::SouthEast,
+            HexDirection::East,
+            HexDirection::NorthEast,
+        ]
+    }
+
+    pub fn from_index(index: usize) -> HexDirection {
+        Self::all()[index%6]
+    }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn opposite(self) -> HexDirection {
+        Self::from_index(self.index() + 3)
+    }
+
+    pub fn next(self) -> HexDirection {
+        Self::from_index(self.index() + 1)
+    }
+
+    pub fn previous(self) -> HexDirection {
+        Self::from_index(self.index() + 5)
+    }
+
+    pub fn offset(self) -> HexCoordinate {
+        HEX_DIRECTIONS[self.index()]
+    }
+
+    //offset from a cell's center to the center of its neighbor in this direction
+    pub fn vector(self, layout: &HexLayout) -> Vec3 {
+        self.first_corner(layout) + self.second_corner(layout)
+    }
+
+    //corners are taken counterclockwise, so the first corner is the one shared with previous()
+    pub fn first_corner(self, layout: &HexLayout) -> Vec3 {
+        layout.corners[self.index()]
+    }
+
+    pub fn second_corner(self, layout: &HexLayout) -> Vec3 {
+        layout.corners[self.index()+1]
+    }
+
+    //compass name of the direction as it appears on screen
+    pub fn name(self, orientation: HexOrientation) -> &'static str {
+        match orientation {
+            HexOrientation::PointyTop => ["NW", "W", "SW", "SE", "E", "NE"][self.index()],
+            HexOrientation::FlatTop   => ["NW", "SW", "S", "SE", "NE", "N"][self.index()],
+        }
+    }
+
+    //name of first_corner(), which is what height_refs[dir] refers to
+    pub fn corner_name(self, orientation: HexOrientation) -> &'static str {
+        match orientation {
+            HexOrientation::PointyTop => ["N", "NW", "SW", "S", "SE", "NE"][self.index()],
+            HexOrientation::FlatTop   => ["NW", "W", "SW", "SE", "E", "NE"][self.index()],
+        }
+    }
+}
+
+//six values, one per HexDirection
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DirectionArray<T>(pub [T; 6]);
+
+impl<T> DirectionArray<T> {
+    pub fn iter(&self) -> impl Iterator<Item = (HexDirection, &T)> {
+        HexDirection::all().into_iter().zip(self.0.iter())
+    }
+}
+
+impl<T> Index<HexDirection> for DirectionArray<T> {
+    type Output = T;
+    fn index(&self, dir: HexDirection) -> &T {
+        &self.0[dir.index()]
+    }
+}
+
+impl<T> IndexMut<HexDirection> for DirectionArray<T> {
+    fn index_mut(&mut self, dir: HexDirection) -> &mut T {
+        &mut self.0[dir.index()]
+    }
+}
 
 static COLOR1: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
 static COLOR2: [f32; 4] = [0.0, 1.0, 0.0, 1.0];
 static COLOR3: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
 
 static OUTER_RADIUS: f32 = 10.0;
-static INNER_RADIUS: f32 = OUTER_RADIUS * 0.866025404;
-
 static SOLID_FACTOR: f32 = 0.8;
-static BLEND_FACTOR: f32 = 1.0 - SOLID_FACTOR;
-static HEX_CORNERS: [Vec3; 7] = [
-    Vec3::new(0.0, 0.0, -OUTER_RADIUS),
-    Vec3::new(-INNER_RADIUS, 0.0, -0.5 * OUTER_RADIUS),
-    Vec3::new(-INNER_RADIUS, 0.0,  0.5 * OUTER_RADIUS),
-    Vec3::new(0.0, 0.0, OUTER_RADIUS),
-    Vec3::new( INNER_RADIUS, 0.0,  0.5 * OUTER_RADIUS),
-    Vec3::new( INNER_RADIUS, 0.0, -0.5 * OUTER_RADIUS),
-    Vec3::new(0.0, 0.0, -OUTER_RADIUS)
-];
+static ELEVATION_STEP: f32 = OUTER_RADIUS/4.0;
+//positions and normals are rounded to multiples of 1/WELD_PRECISION before welding compares them, because
+//the triangulation computes the same point from different corners and doesn't always get the same bits.
+//points that round differently stay apart however close they are.
+static WELD_PRECISION: f32 = 1024.0;
+//segments along each side of a cell's triangles and along its connection strips
+pub const DEFAULT_SUBDIVISIONS: u32 = 3;
+pub const MAX_SUBDIVISIONS: u32 = 8;
 
-static HEX_NORMALS: [Vec2; 7] = [
-    Vec2::new( 0.5, -0.866025404),
-    Vec2::new(-0.5, -0.866025404),
-    Vec2::new(-1.0, 0.0),
-    Vec2::new(-0.5,  0.866025404),
-    Vec2::new( 0.5,  0.866025404),
-    Vec2::new( 1.0,0.0),
-    Vec2::new( 0.5, -0.866025404)
-];
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HexOrientation {
+    //a corner points north
+    #[default]
+    PointyTop,
+    //an edge faces north
+    FlatTop,
+}
+
+//world space geometry of a single hex
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HexLayout {
+    orientation: HexOrientation,
+    //how HexGrid::cells is indexed
+    offset: OffsetLayout,
+    //the east edge of the map is joined to the west edge
+    wrap_x: bool,
+    //center to corner distance
+    outer_radius: f32,
+    //fraction of the radius covered by the flat part of a cell, the rest is blended with its neighbors
+    solid_factor: f32,
+    //world space height of one unit in HexGrid::heights
+    elevation_step: f32,
+    //counterclockwise from the corner shared by the NorthEast and NorthWest edges, the first corner repeated at the end
+    corners: [Vec3; 7],
+    //outward normals of the two edges meeting at each corner, as [previous edge, next edge]
+    corner_normals: [[Vec2; 2]; 6],
+}
+
+impl HexLayout {
+    //rows for pointy-top hexes and columns for flat-top hexes, odd ones shifted
+    pub fn new(orientation: HexOrientation) -> HexLayout {
+        let offset = match orientation {
+            HexOrientation::PointyTop => OffsetLayout::OddR,
+            HexOrientation::FlatTop => OffsetLayout::OddQ,
+        };
+        HexLayout::build(orientation, OUTER_RADIUS, SOLID_FACTOR, ELEVATION_STEP).with_offset_layout(offset)
+    }
+
+    pub fn with_offset_layout(self, offset: OffsetLayout) -> HexLayout {
+        HexLayout { offset, ..self }
+    }
+
+    //see can_wrap_x, grids with a layout that can't wrap panic when this is set
+    pub fn with_wrap_x(self, wrap_x: bool) -> HexLayout {
+        HexLayout { wrap_x, ..self }
+    }
+
+    pub fn with_outer_radius(self, outer_radius: f32) -> HexLayout {
+        assert!(outer_radius > 0.0, "outer radius must be positive");
+        HexLayout {
+            offset: self.offset,
+            wrap_x: self.wrap_x,
+            ..HexLayout::build(self.orientation, outer_radius, self.solid_factor, self.elevation_step)
+        }
+    }
+
+    pub fn with_solid_factor(self, solid_factor: f32) -> HexLayout {
+        assert!(solid_factor > 0.0 && solid_factor <= 1.0, "solid factor must be in (0, 1]");
+        HexLayout { solid_factor, ..self }
+    }
+
+    pub fn with_elevation_step(self, elevation_step: f32) -> HexLayout {
+        HexLayout { elevation_step, ..self }
+    }
+
+    fn build(orientation: HexOrientation, outer_radius: f32, solid_factor: f32, elevation_step: f32) -> HexLayout {
+        let start_angle = match orientation {
+            HexOrientation::PointyTop => 90.0_f32,
+            HexOrientation::FlatTop => 120.0_f32,
+        };
+        let corners = std::array::from_fn(|i| {
+            let angle = (start_angle + 60.0*i as f32).to_radians();
+            Vec3::new(angle.cos(), 0.0, -angle.sin())*outer_radius
+        });
+        let edge_normal = |i: usize| {
+            let v = corners[i%6] + corners[i%6+1];
+            Vec2::new(v.x, v.z).normalize()
+        };
+        let corner_normals = std::array::from_fn(|i| [edge_normal(i+5), edge_normal(i)]);
+        HexLayout {
+            orientation,
+            offset: OffsetLayout::default(),
+            wrap_x: false,
+            outer_radius,
+            solid_factor,
+            elevation_step,
+            corners,
+            corner_normals
+        }
+    }
+
+    pub fn orientation(&self) -> HexOrientation {
+        self.orientation
+    }
+
+    pub fn offset_layout(&self) -> OffsetLayout {
+        self.offset
+    }
+
+    pub fn wraps_x(&self) -> bool {
+        self.wrap_x
+    }
+
+    //rows of pointy-top hexes and columns of flat-top hexes are the only ways of storing hexes that keep
+    //the cells straight east-west, any other pair slants the map and the copies of it when wrapping
+    pub fn can_wrap_x(&self) -> bool {
+        self.offset.is_rows() == (self.orientation == HexOrientation::PointyTop)
+    }
+
+    pub fn outer_radius(&self) -> f32 {
+        self.outer_radius
+    }
+
+    //center to edge distance
+    #[allow(clippy::excessive_precision)]
+    pub fn inner_radius(&self) -> f32 {
+        self.outer_radius*0.866025404
+    }
+
+    pub fn solid_factor(&self) -> f32 {
+        self.solid_factor
+    }
+
+    pub fn blend_factor(&self) -> f32 {
+        1.0 - self.solid_factor
+    }
+
+    pub fn elevation_step(&self) -> f32 {
+        self.elevation_step
+    }
+
+    pub fn corner(&self, index: usize) -> Vec3 {
+        self.corners[index%6]
+    }
+}
+
+impl Default for HexLayout {
+    fn default() -> HexLayout {
+        HexLayout::new(HexOrientation::default())
+    }
+}
+
+//ways of storing hexes in a rectangular array. rows run east-west and columns run north-south,
+//the shifted rows (columns) are moved half a cell east (north) relative to the others.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum OffsetLayout {
+    #[default]
+    OddR,
+    EvenR,
+    OddQ,
+    EvenQ,
+}
+
+impl OffsetLayout {
+    pub fn is_rows(self) -> bool {
+        matches!(self, OffsetLayout::OddR | OffsetLayout::EvenR)
+    }
 
-//TODO: IMPLEMENT HEXAGONAL COORDINATE STYLES - AXIAL AND OFFSET. IMPLEMENT INDEXING WITH THIS
+    pub fn to_hex(self, offset: OffsetCoordinate) -> HexCoordinate {
+        let (x, z) = (offset.x as i32, offset.z as i32);
+        match self {
+            OffsetLayout::OddR  => HexCoordinate::new(x - (z - (z&1))/2, z),
+            OffsetLayout::EvenR => HexCoordinate::new(x - (z + (z&1))/2, z),
+            OffsetLayout::OddQ  => HexCoordinate::new(x, z - (x - (x&1))/2),
+            OffsetLayout::EvenQ => HexCoordinate::new(x, z - (x + (x&1))/2),
+        }
+    }
+
+    //hexes left of or below the origin have no offset coordinate
+    pub fn from_hex(self, hex: HexCoordinate) -> Option<OffsetCoordinate> {
+        let offset = self.signed_offset(hex);
+        if offset.x < 0 || offset.y < 0 {
+            return None;
+        }
+        Some(OffsetCoordinate{x: offset.x as usize, z: offset.y as usize})
+    }
+
+    fn signed_offset(self, hex: HexCoordinate) -> IVec2 {
+        let (q, r) = (hex.x, hex.z);
+        match self {
+            OffsetLayout::OddR  => IVec2::new(q + (r - (r&1))/2, r),
+            OffsetLayout::EvenR => IVec2::new(q + (r + (r&1))/2, r),
+            OffsetLayout::OddQ  => IVec2::new(q, r + (q - (q&1))/2),
+            OffsetLayout::EvenQ => IVec2::new(q, r + (q + (q&1))/2),
+        }
+    }
+}
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct OffsetCoordinate {
     pub x: usize,
     pub z: usize
 }
 
 impl OffsetCoordinate {
-    pub fn from_hex(hex: HexCoordinate) -> OffsetCoordinate {
-        let x = hex.x + (hex.z - (hex.z%2)) / 2;
-        let z = hex.z;
-        OffsetCoordinate{x: x as usize, z: z as usize}
+    pub fn new(x: usize, z: usize) -> OffsetCoordinate {
+        OffsetCoordinate{x, z}
+    }
+
+    pub fn from_hex(hex: HexCoordinate, layout: OffsetLayout) -> Option<OffsetCoordinate> {
+        layout.from_hex(hex)
+    }
+}
+
+//doubled-width coordinates (x = 2q + r, z = r) for row layouts, doubled-height (x = q, z = 2r + q) for
+//column layouts. neighbors along a row (column) are two apart, and x + z is always even.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DoubledCoordinate {
+    pub x: i32,
+    pub z: i32
+}
+
+impl DoubledCoordinate {
+    pub fn from_hex(hex: HexCoordinate, layout: OffsetLayout) -> DoubledCoordinate {
+        if layout.is_rows() {
+            DoubledCoordinate{x: 2*hex.x + hex.z, z: hex.z}
+        } else {
+            DoubledCoordinate{x: hex.x, z: 2*hex.z + hex.x}
+        }
+    }
+
+    //None if x + z is odd
+    pub fn to_hex(self, layout: OffsetLayout) -> Option<HexCoordinate> {
+        if (self.x + self.z)&1 != 0 {
+            return None;
+        }
+        if layout.is_rows() {
+            Some(HexCoordinate::new((self.x - self.z)/2, self.z))
+        } else {
+            Some(HexCoordinate::new(self.x, (self.z - self.x)/2))
+        }
+    }
+}
+
+//a point of the heights lattice, shared by up to three cells.
+//the lattice is the grid's doubled coordinates with the corners in between, shifted to start at (0, 0).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HexCorner {
+    pub x: usize,
+    pub z: usize
+}
+
+impl HexCorner {
+    pub fn new(x: usize, z: usize) -> HexCorner {
+        HexCorner{x, z}
+    }
+}
+
+//the boundary between a hex and its neighbor in some direction.
+//stored as seen from whichever of the two cells it is a NorthWest, West or SouthWest edge of,
+//so both cells name the same edge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HexEdge {
+    hex: HexCoordinate,
+    dir: HexDirection
+}
+
+impl HexEdge {
+    pub fn new(hex: HexCoordinate, dir: HexDirection) -> HexEdge {
+        if dir <= HexDirection::SouthWest {
+            HexEdge{hex, dir}
+        } else {
+            HexEdge{hex: hex.neighbor(dir), dir: dir.opposite()}
+        }
+    }
+
+    pub fn hex(self) -> HexCoordinate {
+        self.hex
     }
 
-    pub fn from_position(position: Vec3) -> OffsetCoordinate {
-        Self::from_hex(HexCoordinate::from_position(position))
+    pub fn direction(self) -> HexDirection {
+        self.dir
+    }
+
+    //hex() and its neighbor across the edge
+    pub fn cells(self) -> [HexCoordinate; 2] {
+        [self.hex, self.hex.neighbor(self.dir)]
+    }
+
+    //world positions of the ends of the edge, before elevation
+    pub fn corners(self, layout: &HexLayout) -> [Vec3; 2] {
+        let center = self.hex.position(layout);
+        [center + self.dir.first_corner(layout), center + self.dir.second_corner(layout)]
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+//features that run along an edge
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct HexEdgeData {
+    pub river: bool,
+    pub road: bool,
+    pub wall: bool,
+}
+
+//in HexDirection order
+static HEX_DIRECTIONS: [HexCoordinate; 6] = [
+    HexCoordinate::new(-1,  1),
+    HexCoordinate::new(-1,  0),
+    HexCoordinate::new( 0, -1),
+    HexCoordinate::new( 1, -1),
+    HexCoordinate::new( 1,  0),
+    HexCoordinate::new( 0,  1),
+];
+
+//cube coordinates, x + y + z == 0. x increases to the east, z increases with the row of the cell.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
 pub struct HexCoordinate {
     x: i32,
     y: i32,
@@ -76,26 +443,57 @@ pub struct HexCoordinate {
 }
 
 impl HexCoordinate {
-    fn new(x: i32, z: i32) -> HexCoordinate {
+    pub const ZERO: HexCoordinate = HexCoordinate::new(0, 0);
+
+    pub const fn new(x: i32, z: i32) -> HexCoordinate {
         HexCoordinate{
             x,
             y: -x - z,
             z
         }
     }
-    fn from_position(position: Vec3) -> HexCoordinate {
-        let mut x = position.x / (INNER_RADIUS * 2.0);
-        let mut y = -x;
-        let offset = -position.z / (OUTER_RADIUS * 3.0);
-        x -= offset;
-        y -= offset;
+
+    pub fn from_offset(offset: OffsetCoordinate, layout: OffsetLayout) -> HexCoordinate {
+        layout.to_hex(offset)
+    }
+
+    pub fn x(&self) -> i32 {
+        self.x
+    }
+
+    pub fn y(&self) -> i32 {
+        self.y
+    }
+
+    pub fn z(&self) -> i32 {
+        self.z
+    }
+
+    //center of the hex in world space
+    pub fn position(self, layout: &HexLayout) -> Vec3 {
+        HexDirection::East.vector(layout)*self.x as f32 + HexDirection::NorthEast.vector(layout)*self.z as f32
+    }
+
+    pub fn from_position(position: Vec3, layout: &HexLayout) -> HexCoordinate {
+        //invert position() for the two axis vectors
+        let e = HexDirection::East.vector(layout);
+        let n = HexDirection::NorthEast.vector(layout);
+        let det = e.x*n.z - n.x*e.z;
+        let x = (position.x*n.z - position.z*n.x)/det;
+        let z = (e.x*position.z - e.z*position.x)/det;
+        HexCoordinate::round(x, z)
+    }
+
+    //nearest hex to a fractional cube coordinate
+    pub fn round(x: f32, z: f32) -> HexCoordinate {
+        let y = -x - z;
         let mut ix = x.round() as i32;
         let iy = y.round() as i32;
-        let mut iz = (-x - y).round() as i32;
+        let mut iz = z.round() as i32;
         if ix + iy + iz != 0 {
             let dx = (x - ix as f32).abs();
             let dy = (y - iy as f32).abs();
-            let dz = (-x - y - iz as f32).abs();
+            let dz = (z - iz as f32).abs();
 
             if dx > dy && dx > dz {
                 ix = -iy - iz;
@@ -106,30 +504,187 @@ impl HexCoordinate {
 
         HexCoordinate::new(ix, iz)
     }
+
+    //the hex a fraction t of the way from self to other.
+    //nudged slightly so that points exactly on an edge round consistently.
+    pub fn lerp(self, other: HexCoordinate, t: f32) -> HexCoordinate {
+        const NUDGE_X: f32 = 1e-3;
+        const NUDGE_Z: f32 = -3e-3;
+        HexCoordinate::round(
+            self.x as f32 + (other.x - self.x) as f32 * t + NUDGE_X,
+            self.z as f32 + (other.z - self.z) as f32 * t + NUDGE_Z
+        )
+    }
+
+    //hexes a straight line from the center of self to the center of other passes through, including both ends
+    pub fn line_to(self, other: HexCoordinate) -> impl Iterator<Item = HexCoordinate> {
+        let n = self.distance(other);
+        (0..=n).map(move |i| {
+            if n == 0 {
+                self
+            } else {
+                self.lerp(other, i as f32 / n as f32)
+            }
+        })
+    }
+
+    pub fn neighbor(self, dir: HexDirection) -> HexCoordinate {
+        self + dir.offset()
+    }
+
+    pub fn neighbors(self) -> DirectionArray<HexCoordinate> {
+        DirectionArray(HEX_DIRECTIONS.map(|d| self + d))
+    }
+
+    pub fn length(self) -> i32 {
+        self.x.abs().max(self.y.abs()).max(self.z.abs())
+    }
+
+    pub fn distance(self, other: HexCoordinate) -> i32 {
+        (self - other).length()
+    }
+
+    //hexes at exactly `radius` from self, counterclockwise starting from the east
+    pub fn ring(self, radius: u32) -> impl Iterator<Item = HexCoordinate> {
+        let start = self + HexDirection::East.offset()*radius as i32;
+        let center = (radius == 0).then_some(self);
+        center.into_iter().chain(
+            HexDirection::all()
+                .into_iter()
+                .flat_map(move |dir| std::iter::repeat_n(dir, radius as usize))
+                .scan(start, |hex, dir| {
+                    let current = *hex;
+                    *hex = hex.neighbor(dir);
+                    Some(current)
+                })
+        )
+    }
+
+    //the same hexes as range, ordered ring by ring outwards from self
+    pub fn spiral(self, radius: u32) -> impl Iterator<Item = HexCoordinate> {
+        (0..=radius).flat_map(move |r| self.ring(r))
+    }
+
+    //hexes within `radius` of self, ordered by x then z
+    pub fn range(self, radius: u32) -> impl Iterator<Item = HexCoordinate> {
+        let n = radius as i32;
+        (-n..=n).flat_map(move |dx| {
+            ((-n).max(-dx - n)..=n.min(-dx + n)).map(move |dz| self + HexCoordinate::new(dx, dz))
+        })
+    }
+}
+
+impl Add for HexCoordinate {
+    type Output = HexCoordinate;
+    fn add(self, rhs: HexCoordinate) -> HexCoordinate {
+        HexCoordinate::new(self.x + rhs.x, self.z + rhs.z)
+    }
 }
 
-struct EdgeVertices {
-    v1: Vec3,
-    v2: Vec3,
-    v3: Vec3,
-    v4: Vec3,
+impl Sub for HexCoordinate {
+    type Output = HexCoordinate;
+    fn sub(self, rhs: HexCoordinate) -> HexCoordinate {
+        HexCoordinate::new(self.x - rhs.x, self.z - rhs.z)
+    }
 }
 
+impl Mul<i32> for HexCoordinate {
+    type Output = HexCoordinate;
+    fn mul(self, rhs: i32) -> HexCoordinate {
+        HexCoordinate::new(self.x * rhs, self.z * rhs)
+    }
+}
+
+impl Neg for HexCoordinate {
+    type Output = HexCoordinate;
+    fn neg(self) -> HexCoordinate {
+        HexCoordinate::new(-self.x, -self.z)
+    }
+}
+
+//subdivisions + 1 evenly spaced points from the first to the last
+struct EdgeVertices(Vec<Vec3>);
+
 impl EdgeVertices {
-    fn new(v1: Vec3, v4: Vec3) -> EdgeVertices {
-        let v2 = v1.lerp(v4, 1.0/3.0);
-        let v3 = v1.lerp(v4, 2.0/3.0);
-        EdgeVertices{ v1, v2, v3, v4 }
+    fn new(first: Vec3, last: Vec3, subdivisions: u32) -> EdgeVertices {
+        EdgeVertices(
+            (0..=subdivisions)
+                .map(|i| first.lerp(last, i as f32/subdivisions as f32))
+                .collect()
+        )
+    }
+
+    fn first(&self) -> Vec3 {
+        self.0[0]
+    }
+
+    fn last(&self) -> Vec3 {
+        self.0[self.0.len() - 1]
     }
 }
 
+#[derive(Clone)]
 pub struct HexCell {
-    neighbor_cell_refs: [Option<(usize, usize)>; 6],
-    pub height_refs: [(usize, usize); 6],
-    terrain: u32,
+    neighbor_cell_refs: DirectionArray<Option<(usize, usize)>>,
+    pub height_refs: DirectionArray<HexCorner>,
     position: Vec3,
 }
 
+//a value for every cell of a grid, indexed like HexGrid::cells.
+//made with HexGrid::layer or HexGrid::layer_from_fn so that it has the same shape as the grid.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CellLayer<T> {
+    values: Vec<Vec<Option<T>>>
+}
+
+impl<T> CellLayer<T> {
+    pub fn get(&self, offset: OffsetCoordinate) -> Option<&T> {
+        self.values.get(offset.x)?.get(offset.z)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, offset: OffsetCoordinate) -> Option<&mut T> {
+        self.values.get_mut(offset.x)?.get_mut(offset.z)?.as_mut()
+    }
+
+    //false if the grid has no cell at offset
+    pub fn set(&mut self, offset: OffsetCoordinate, value: T) -> bool {
+        match self.get_mut(offset) {
+            Some(v) => {
+                *v = value;
+                true
+            }
+            None => false
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (OffsetCoordinate, &T)> {
+        self.values.iter().enumerate().flat_map(|(x, column)| {
+            column.iter().enumerate().filter_map(move |(z, v)| Some((OffsetCoordinate::new(x, z), v.as_ref()?)))
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (OffsetCoordinate, &mut T)> {
+        self.values.iter_mut().enumerate().flat_map(|(x, column)| {
+            column.iter_mut().enumerate().filter_map(move |(z, v)| Some((OffsetCoordinate::new(x, z), v.as_mut()?)))
+        })
+    }
+}
+
+//panics if the grid has no cell at offset
+impl<T> Index<OffsetCoordinate> for CellLayer<T> {
+    type Output = T;
+    fn index(&self, offset: OffsetCoordinate) -> &T {
+        self.get(offset).expect("no cell at offset")
+    }
+}
+
+impl<T> IndexMut<OffsetCoordinate> for CellLayer<T> {
+    fn index_mut(&mut self, offset: OffsetCoordinate) -> &mut T {
+        self.get_mut(offset).expect("no cell at offset")
+    }
+}
+
+#[derive(Clone, Default)]
 struct HexMeshData{
     vertices: Vec<Vec3>,
     colors: Vec<[f32; 4]>,
@@ -138,99 +693,497 @@ struct HexMeshData{
     normals: Vec<Vec3>
 }
 
-#[derive(Resource)]
+impl HexMeshData {
+    //the triangulation gives every triangle its own vertices, this shares the ones with the same
+    //position, normal, color and terrain. the first of each is kept.
+    fn weld(&mut self) {
+        let quantize = |v: Vec3| (v*WELD_PRECISION).round().as_ivec3().to_array();
+        let mut welded = HexMeshData::default();
+        let mut index = HashMap::new();
+        let remap = (0..self.vertices.len())
+            .map(|i| {
+                let key = (
+                    quantize(self.vertices[i]),
+                    quantize(self.normals[i]),
+                    self.colors[i].map(f32::to_bits),
+                    self.vert_terrain[i].to_array()
+                );
+                *index.entry(key).or_insert_with(|| {
+                    welded.vertices.push(self.vertices[i]);
+                    welded.normals.push(self.normals[i]);
+                    welded.colors.push(self.colors[i]);
+                    welded.vert_terrain.push(self.vert_terrain[i]);
+                    welded.vertices.len() as u32 - 1
+                })
+            })
+            .collect::<Vec<u32>>();
+        welded.triangles = self.triangles.iter().map(|&i| remap[i as usize]).collect();
+        *self = welded;
+    }
+}
+
+#[derive(Asset, Resource, TypePath, Clone)]
 pub struct HexGrid {
-    pub cells: Vec<Vec<HexCell>>,
+    //indexed by OffsetCoordinate, None where the map has no cell
+    pub cells: Vec<Vec<Option<HexCell>>>,
+    //indexed by HexCorner
     pub heights: Vec<Vec<i32>>,
+    //keyed by HexGrid::canonical_edge, edges without an entry have the default data
+    pub edges: HashMap<HexEdge, HexEdgeData>,
+    //index into a TerrainRegistry for each cell
+    pub terrain: CellLayer<u32>,
+    layout: HexLayout,
+    //lattice position of HexCorner(0, 0)
+    lattice_origin: IVec2,
+    //counts edits made through set_height_at_corner, set_terrain and mark_cell_dirty
+    generation: u64,
+    //generation of the last edit that changed each cell's triangulation
+    cell_generations: HashMap<OffsetCoordinate, u64>,
 }
 impl HexGrid {
-    pub(crate) fn new(cell_count_x: usize, cell_count_z: usize) -> HexGrid {
-        let mut heights = vec![vec![2; cell_count_z+1]; 2*cell_count_x];
-        // for z in 0..(cell_count_z+1) {
-        //     for x in 0..(2*cell_count_x) {
-        //         heights[x][z] = (x+z) as i8;
-        //     }
-        // }
-
-
-        let mut cells = (0..cell_count_x)
-            .map(|x| (0..cell_count_z)
-                .map(|z| {
-                HexGrid::create_cell(x, z, cell_count_x, cell_count_z)
-        }).collect::<Vec<HexCell>>()).collect::<Vec<Vec<HexCell>>>();
-        // for x in 0..cell_count_x {
-        //     for z in 0..cell_count_z {
-        //         if random::<f32>() > 0.5 {
-        //             for (ref_x, ref_z) in cells[x][z].height_refs {
-        //                 heights[ref_x][ref_z] += 1;
-        //             }
-        //         }
-        //     }
-        // }
-        // for (ref_x, ref_z) in cells[1][1].height_refs {
-        //     heights[ref_x][ref_z] = 1;
-        // }
-        for x in 0..cell_count_x {
-            for z in 0..cell_count_z {
-                if x > 0 {
-                    cells[x][z].neighbor_cell_refs[W] = Some((x-1, z));
-                    cells[x-1][z].neighbor_cell_refs[E] = Some((x, z));
+    //terrain 0 and 1 scattered at random, the same seed always gives the same grid
+    pub fn new(cell_count_x: usize, cell_count_z: usize, seed: u64) -> HexGrid {
+        let mut grid = HexGrid::with_layout(cell_count_x, cell_count_z, HexLayout::default());
+        grid.randomize_terrain(2, &mut HexGrid::rng(seed));
+        grid
+    }
+
+    //the generator HexGrid::new uses, the same on every platform
+    pub fn rng(seed: u64) -> ChaCha8Rng {
+        ChaCha8Rng::seed_from_u64(seed)
+    }
+
+    pub fn with_layout(cell_count_x: usize, cell_count_z: usize, layout: HexLayout) -> HexGrid {
+        HexGrid::from_mask(&vec![vec![true; cell_count_z]; cell_count_x], layout)
+    }
+
+    //hexes within `radius` of a center cell
+    pub fn hexagon(radius: u32, layout: HexLayout) -> HexGrid {
+        HexGrid::from_hexes(HexCoordinate::ZERO.range(radius), layout)
+    }
+
+    //`size_x` cells along the cube x axis by `size_z` cells along the cube z axis
+    pub fn parallelogram(size_x: u32, size_z: u32, layout: HexLayout) -> HexGrid {
+        let hexes = (0..size_x as i32)
+            .flat_map(|x| (0..size_z as i32).map(move |z| HexCoordinate::new(x, z)));
+        HexGrid::from_hexes(hexes, layout)
+    }
+
+    //`size` cells along each side
+    pub fn triangle(size: u32, layout: HexLayout) -> HexGrid {
+        let size = size as i32;
+        let hexes = (0..size)
+            .flat_map(move |x| (0..size - x).map(move |z| HexCoordinate::new(x, z)));
+        HexGrid::from_hexes(hexes, layout)
+    }
+
+    //a grid containing the given hexes, moved so that they start at offset (0, 0).
+    //shifting by an odd number of rows (columns) would change the shape, so there may be one empty row (column).
+    pub fn from_hexes(hexes: impl IntoIterator<Item = HexCoordinate>, layout: HexLayout) -> HexGrid {
+        let offsets = hexes.into_iter()
+            .map(|hex| layout.offset.signed_offset(hex))
+            .collect::<Vec<IVec2>>();
+        let (min, max) = offsets.iter()
+            .fold((IVec2::MAX, IVec2::MIN), |(min, max), &o| (min.min(o), max.max(o)));
+        if offsets.is_empty() {
+            return HexGrid::from_mask(&[], layout);
+        }
+        let shift = if layout.offset.is_rows() {
+            IVec2::new(-min.x, -min.y + (min.y&1))
+        } else {
+            IVec2::new(-min.x + (min.x&1), -min.y)
+        };
+        let size = max + shift + IVec2::ONE;
+        let mut mask = vec![vec![false; size.y as usize]; size.x as usize];
+        for offset in offsets {
+            let offset = offset + shift;
+            mask[offset.x as usize][offset.y as usize] = true;
+        }
+        HexGrid::from_mask(&mask, layout)
+    }
+
+    //mask[x][z] says whether there is a cell at offset (x, z)
+    pub fn from_mask(mask: &[Vec<bool>], layout: HexLayout) -> HexGrid {
+        let cell_count_x = mask.len();
+        let hexes = mask.iter().enumerate()
+            .map(|(x, column)| column.iter().enumerate()
+                .map(|(z, &present)| present.then(|| layout.offset.to_hex(OffsetCoordinate::new(x, z))))
+                .collect::<Vec<Option<HexCoordinate>>>())
+            .collect::<Vec<Vec<Option<HexCoordinate>>>>();
+
+        //the lattice is sized to fit the corners of every cell.
+        //when wrapping, the corners east of the last cell in a row (column) are the first ones.
+        let (min, max) = hexes.iter().flatten().flatten()
+            .flat_map(|&hex| Self::lattice_refs(hex, layout.offset))
+            .fold((IVec2::MAX, IVec2::MIN), |(min, max), r| (min.min(r), max.max(r)));
+        //an empty lattice if there are no cells
+        let (min, max) = if min.x > max.x { (IVec2::ZERO, IVec2::NEG_ONE) } else { (min, max) };
+        let wrap_x = layout.wrap_x.then(|| {
+            assert!(layout.can_wrap_x(), "only pointy-top rows and flat-top columns can wrap");
+            if layout.offset.is_rows() {
+                2*cell_count_x as i32
+            } else {
+                assert!(cell_count_x.is_multiple_of(2), "wrapping column layouts need an even number of columns");
+                cell_count_x as i32
+            }
+        });
+        let width = wrap_x.unwrap_or(max.x - min.x + 1);
+        let heights = vec![vec![2; (max.y - min.y + 1) as usize]; width as usize];
+
+        let cells = hexes.iter()
+            .map(|column| column.iter()
+                .map(|hex| hex.map(|hex| {
+                    let height_refs = Self::lattice_refs(hex, layout.offset).map(|r| {
+                        let r = r - min;
+                        HexCorner::new(wrap_x.map_or(r.x, |w| r.x.rem_euclid(w)) as usize, r.y as usize)
+                    });
+                    HexGrid::create_cell(hex, DirectionArray(height_refs), &layout)
+        })).collect::<Vec<Option<HexCell>>>()).collect::<Vec<Vec<Option<HexCell>>>>();
+        let mut grid = HexGrid {
+            cells,
+            heights,
+            edges: HashMap::new(),
+            terrain: CellLayer::default(),
+            layout,
+            lattice_origin: min,
+            generation: 0,
+            cell_generations: HashMap::new()
+        };
+        for x in 0..grid.cells.len() {
+            for z in 0..grid.cells[x].len() {
+                let hex = grid.hex_coordinate(OffsetCoordinate::new(x, z));
+                let neighbor_cell_refs = DirectionArray(HexDirection::all().map(|dir| {
+                    grid.offset_coordinate(hex.neighbor(dir)).map(|offset| (offset.x, offset.z))
+                }));
+                if let Some(cell) = &mut grid.cells[x][z] {
+                    cell.neighbor_cell_refs = neighbor_cell_refs;
                 }
-                if z > 0 {
-                    if z%2 == 0 {
-                        cells[x][z  ].neighbor_cell_refs[SW] = Some((x, z-1));
-                        cells[x][z-1].neighbor_cell_refs[NE] = Some((x, z));
-                        if x > 0 {
-                            cells[x  ][z  ].neighbor_cell_refs[SE] = Some((x-1, z-1));
-                            cells[x-1][z-1].neighbor_cell_refs[NW] = Some((x  , z  ));
-                        }
-                    } else {
-                        cells[x][z  ].neighbor_cell_refs[SE] = Some((x, z-1));
-                        cells[x][z-1].neighbor_cell_refs[NW] = Some((x, z  ));
-                        if x < cell_count_x-1 {
-                            cells[x  ][z  ].neighbor_cell_refs[SW] = Some((x+1, z-1));
-                            cells[x+1][z-1].neighbor_cell_refs[NE] = Some((x  , z  ));
-                        }
-                    }
+            }
+        }
+        grid.terrain = grid.layer(0);
+        grid
+    }
+
+    pub fn layout(&self) -> &HexLayout {
+        &self.layout
+    }
+
+    //position of a cell in `cells` to its cube coordinate
+    pub fn hex_coordinate(&self, offset: OffsetCoordinate) -> HexCoordinate {
+        self.layout.offset.to_hex(offset)
+    }
+
+    //inverse of hex_coordinate, None if the hex isn't in `cells`.
+    //when wrapping, every hex east or west of the map has a copy inside it.
+    pub fn offset_coordinate(&self, hex: HexCoordinate) -> Option<OffsetCoordinate> {
+        let mut offset = self.layout.offset.signed_offset(hex);
+        if self.layout.wrap_x && !self.cells.is_empty() {
+            offset.x = offset.x.rem_euclid(self.cells.len() as i32);
+        }
+        if offset.x < 0 || offset.y < 0 {
+            return None;
+        }
+        Some(OffsetCoordinate::new(offset.x as usize, offset.y as usize)).filter(|&offset| self.contains(offset))
+    }
+
+    //distance between copies of the map when wrapping
+    pub fn wrap_offset(&self) -> Option<Vec3> {
+        if !self.layout.wrap_x {
+            return None;
+        }
+        let width = OffsetCoordinate::new(self.cells.len(), 0);
+        let hex = self.layout.offset.to_hex(width) - self.layout.offset.to_hex(OffsetCoordinate::new(0, 0));
+        Some(hex.position(&self.layout))
+    }
+
+    pub fn contains(&self, offset: OffsetCoordinate) -> bool {
+        self.cell(offset).is_some()
+    }
+
+    pub fn cell(&self, offset: OffsetCoordinate) -> Option<&HexCell> {
+        self.cells.get(offset.x)?.get(offset.z)?.as_ref()
+    }
+
+    //the corners of a cell, indexed by the direction of the edge that starts at each one
+    pub fn cell_corners(&self, offset: OffsetCoordinate) -> Option<DirectionArray<HexCorner>> {
+        self.cell(offset).map(|cell| cell.height_refs)
+    }
+
+    //the cells that share a corner
+    pub fn corner_cells(&self, corner: HexCorner) -> impl Iterator<Item = OffsetCoordinate> + '_ {
+        let position = IVec2::new(corner.x as i32, corner.z as i32) + self.lattice_origin;
+        HexDirection::all().into_iter()
+            .zip(Self::lattice_corners(self.layout.offset))
+            .filter_map(move |(dir, c)| {
+                let base = position - c;
+                let hex = DoubledCoordinate{x: base.x, z: base.y}.to_hex(self.layout.offset)?;
+                let offset = self.offset_coordinate(hex)?;
+                (self.cell(offset)?.height_refs[dir] == corner).then_some(offset)
+            })
+    }
+
+    pub fn height_at_corner(&self, corner: HexCorner) -> Option<i32> {
+        self.heights.get(corner.x)?.get(corner.z).copied()
+    }
+
+    //false if the corner isn't in the lattice
+    pub fn set_height_at_corner(&mut self, corner: HexCorner, height: i32) -> bool {
+        match self.heights.get_mut(corner.x).and_then(|column| column.get_mut(corner.z)) {
+            Some(h) => {
+                *h = height;
+                let cells = self.corner_cells(corner).collect::<Vec<OffsetCoordinate>>();
+                for offset in cells {
+                    self.mark_cell_dirty(offset);
                 }
+                true
             }
+            None => false
         }
-        HexGrid {
-            cells,
-            heights
+    }
+
+    //false if the grid has no cell at offset
+    pub fn set_terrain(&mut self, offset: OffsetCoordinate, terrain: u32) -> bool {
+        if !self.terrain.set(offset, terrain) {
+            return false;
         }
+        self.mark_cell_dirty(offset);
+        true
     }
 
-    fn get_height_refs(_x: usize, z: usize, cell_count_x: usize, _cell_count_z: usize) -> [(usize, usize); 6] {
-        let x = 2*(_x%cell_count_x) + z%2;
-        [
-            ((x+1)%(cell_count_x*2), z+1),
-            (x, z + 1),
-            (x, z),
-            ((x+1)%(cell_count_x*2), z),
-            ((x+2)%(cell_count_x*2), z),
-            ((x+2)%(cell_count_x*2), z+1),
-        ]
+    //records that the data of a cell changed, for edits made directly to `heights` or `terrain`.
+    //a cell's connections to its NorthWest, West and SouthWest neighbors and the corners between them
+    //are made by that cell, so its SouthEast, East, NorthEast and NorthWest neighbors change with it.
+    pub fn mark_cell_dirty(&mut self, offset: OffsetCoordinate) {
+        self.generation += 1;
+        let hex = self.hex_coordinate(offset);
+        let readers = [HexDirection::SouthEast, HexDirection::East, HexDirection::NorthEast, HexDirection::NorthWest]
+            .map(|dir| self.offset_coordinate(hex.neighbor(dir)));
+        for offset in readers.into_iter().flatten().chain([offset]) {
+            self.cell_generations.insert(offset, self.generation);
+        }
+    }
+
+    //increases with every tracked edit
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    //cells whose triangulation changed after `generation`
+    pub fn dirty_cells_since(&self, generation: u64) -> impl Iterator<Item = OffsetCoordinate> + '_ {
+        self.cell_generations.iter()
+            .filter(move |(_, &g)| g > generation)
+            .map(|(&offset, _)| offset)
     }
 
-    fn create_cell(_x: usize, _z: usize, cell_count_x: usize, cell_count_z: usize) -> HexCell {
-        let (x, z) = (_x as f32, _z as f32);
-        let position = Vec3::new(
-            (x+z*0.5 - (_z/2) as f32)*INNER_RADIUS*2.0,
-            0.0,
-            -z*OUTER_RADIUS*1.5
+    pub fn chunk_of(offset: OffsetCoordinate, chunk_size: usize) -> UVec2 {
+        UVec2::new((offset.x/chunk_size) as u32, (offset.z/chunk_size) as u32)
+    }
+
+    //chunks are `chunk_size` by `chunk_size` cells, by offset coordinate
+    pub fn chunk_count(&self, chunk_size: usize) -> UVec2 {
+        let cells_z = self.cells.iter().map(Vec::len).max().unwrap_or(0);
+        UVec2::new(self.cells.len().div_ceil(chunk_size) as u32, cells_z.div_ceil(chunk_size) as u32)
+    }
+
+    //position of the middle cell of a chunk, which is outside the map for chunks cut off by its edge
+    pub fn chunk_center(&self, chunk: UVec2, chunk_size: usize) -> Vec3 {
+        let middle = OffsetCoordinate::new(
+            chunk.x as usize*chunk_size + chunk_size/2,
+            chunk.y as usize*chunk_size + chunk_size/2
         );
-        let height_refs = HexGrid::get_height_refs(_x, _z, cell_count_x, cell_count_z);
-        let neighbor_cell_refs = [None; 6];
-        let terrain = if random::<f32>() > 0.5 {
-            0
+        self.hex_coordinate(middle).position(&self.layout)
+    }
+
+    pub fn dirty_chunks_since(&self, generation: u64, chunk_size: usize) -> HashSet<UVec2> {
+        self.dirty_cells_since(generation)
+            .map(|offset| Self::chunk_of(offset, chunk_size))
+            .collect()
+    }
+
+    //whether a cell of the chunk changed its triangulation after `generation`
+    pub fn chunk_dirty_since(&self, chunk: UVec2, generation: u64, chunk_size: usize) -> bool {
+        let (start_x, start_z) = (chunk.x as usize*chunk_size, chunk.y as usize*chunk_size);
+        (start_x..start_x + chunk_size)
+            .flat_map(|x| (start_z..start_z + chunk_size).map(move |z| OffsetCoordinate::new(x, z)))
+            .any(|offset| self.cell_generations.get(&offset).is_some_and(|&g| g > generation))
+    }
+
+    pub fn edge(&self, offset: OffsetCoordinate, dir: HexDirection) -> HexEdge {
+        HexEdge::new(self.hex_coordinate(offset), dir)
+    }
+
+    //the copy of an edge that is a NorthWest, West or SouthWest edge of a hex in the map, or if there is
+    //none the copy whose neighbor is in the map. only differs from the edge itself when wrapping.
+    //None if neither cell is in the map.
+    pub fn canonical_edge(&self, edge: HexEdge) -> Option<HexEdge> {
+        let [hex, neighbor] = edge.cells();
+        if let Some(offset) = self.offset_coordinate(hex) {
+            return Some(HexEdge::new(self.hex_coordinate(offset), edge.dir));
+        }
+        let offset = self.offset_coordinate(neighbor)?;
+        Some(HexEdge::new(self.hex_coordinate(offset), edge.dir.opposite()))
+    }
+
+    //the cells on either side of an edge, in the order of HexEdge::cells
+    pub fn edge_cells(&self, edge: HexEdge) -> [Option<OffsetCoordinate>; 2] {
+        edge.cells().map(|hex| self.offset_coordinate(hex))
+    }
+
+    //the corners at the ends of an edge, in the order of HexEdge::corners
+    pub fn edge_corners(&self, edge: HexEdge) -> Option<[HexCorner; 2]> {
+        let [cell, neighbor] = self.edge_cells(edge);
+        if let Some(cell) = cell.and_then(|offset| self.cell(offset)) {
+            return Some([cell.height_refs[edge.dir], cell.height_refs[edge.dir.next()]]);
+        }
+        let neighbor = self.cell(neighbor?)?;
+        let dir = edge.dir.opposite();
+        Some([neighbor.height_refs[dir.next()], neighbor.height_refs[dir]])
+    }
+
+    pub fn edge_data(&self, edge: HexEdge) -> HexEdgeData {
+        self.canonical_edge(edge)
+            .and_then(|edge| self.edges.get(&edge).copied())
+            .unwrap_or_default()
+    }
+
+    //false if the edge doesn't touch the map
+    pub fn set_edge_data(&mut self, edge: HexEdge, data: HexEdgeData) -> bool {
+        let Some(edge) = self.canonical_edge(edge) else {
+            return false;
+        };
+        if data == HexEdgeData::default() {
+            self.edges.remove(&edge);
         } else {
-            1
+            self.edges.insert(edge, data);
+        }
+        true
+    }
+
+    //every cell gets a terrain in 0..terrain_count, drawn from rng in cell order.
+    //panics if terrain_count is 0.
+    pub fn randomize_terrain(&mut self, terrain_count: u32, rng: &mut impl Rng) {
+        assert!(terrain_count > 0, "there has to be at least one terrain to pick from");
+        for (_, terrain) in self.terrain.iter_mut() {
+            *terrain = rng.gen_range(0..terrain_count);
+        }
+    }
+
+    pub fn iter_cells(&self) -> impl Iterator<Item = (OffsetCoordinate, &HexCell)> {
+        self.cells.iter().enumerate().flat_map(|(x, column)| {
+            column.iter().enumerate().filter_map(move |(z, cell)| Some((OffsetCoordinate::new(x, z), cell.as_ref()?)))
+        })
+    }
+
+    //a new layer with `value` for every cell
+    pub fn layer<T: Clone>(&self, value: T) -> CellLayer<T> {
+        self.layer_from_fn(|_| value.clone())
+    }
+
+    pub fn layer_from_fn<T>(&self, mut f: impl FnMut(OffsetCoordinate) -> T) -> CellLayer<T> {
+        let values = self.cells.iter().enumerate()
+            .map(|(x, column)| column.iter().enumerate()
+                .map(|(z, cell)| cell.as_ref().map(|_| f(OffsetCoordinate::new(x, z))))
+                .collect())
+            .collect();
+        CellLayer{values}
+    }
+
+    pub fn cell_at_position(&self, position: Vec3) -> Option<OffsetCoordinate> {
+        self.offset_coordinate(HexCoordinate::from_position(position, &self.layout))
+    }
+
+    fn cells_in<'a>(&'a self, hexes: impl Iterator<Item = HexCoordinate> + 'a) -> impl Iterator<Item = OffsetCoordinate> + 'a {
+        hexes.filter_map(|hex| self.offset_coordinate(hex))
+    }
+
+    //HexCoordinate::ring, skipping hexes that aren't in cells
+    pub fn cells_in_ring(&self, center: OffsetCoordinate, radius: u32) -> impl Iterator<Item = OffsetCoordinate> + '_ {
+        self.cells_in(self.hex_coordinate(center).ring(radius))
+    }
+
+    pub fn cells_in_spiral(&self, center: OffsetCoordinate, radius: u32) -> impl Iterator<Item = OffsetCoordinate> + '_ {
+        self.cells_in(self.hex_coordinate(center).spiral(radius))
+    }
+
+    pub fn cells_in_range(&self, center: OffsetCoordinate, radius: u32) -> impl Iterator<Item = OffsetCoordinate> + '_ {
+        self.cells_in(self.hex_coordinate(center).range(radius))
+    }
+
+    //terrain height under a world position, None outside the grid
+    pub fn height_at(&self, position: Vec3) -> Option<f32> {
+        let hex = HexCoordinate::from_position(position, &self.layout);
+        let cell = self.cell(self.offset_coordinate(hex)?)?;
+        //move positions on a wrapped copy of the map back onto the cell
+        Some(self.calc_height(position + cell.position - hex.position(&self.layout), cell))
+    }
+
+    //whether an eye `eye_height` above the terrain at the center of `from` can see the point
+    //`eye_height` above the center of `to`. the cells at either end never block the view.
+    pub fn line_of_sight(&self, from: OffsetCoordinate, to: OffsetCoordinate, eye_height: f32) -> bool {
+        const SAMPLES_PER_CELL: i32 = 4;
+        let (Some(from_cell), Some(to_cell)) = (self.cell(from), self.cell(to)) else {
+            return false;
         };
+        let start = from_cell.position;
+        let end = to_cell.position;
+        let start_height = self.calc_height(start, from_cell) + eye_height;
+        let end_height = self.calc_height(end, to_cell) + eye_height;
+
+        let (a, b) = (self.hex_coordinate(from), self.hex_coordinate(to));
+        let samples = a.distance(b)*SAMPLES_PER_CELL;
+        for i in 1..samples {
+            let t = i as f32 / samples as f32;
+            let hex = a.lerp(b, t);
+            if hex == a || hex == b {
+                continue;
+            }
+            let Some(cell) = self.offset_coordinate(hex).and_then(|offset| self.cell(offset)) else {
+                continue;
+            };
+            let sight_height = start_height + (end_height - start_height)*t;
+            //like height_at, a hex past the seam of a wrapped map is a copy of the cell
+            let position = start.lerp(end, t) + cell.position - hex.position(&self.layout);
+            if self.calc_height(position, cell) > sight_height {
+                return false;
+            }
+        }
+        true
+    }
+
+    //corners of a cell in the heights lattice, before shifting the lattice to start at 0.
+    //for row layouts, rows of corners zigzag between the bottom corners of one row of cells
+    //and the top corners of the row below it, and are indexed by doubled-width coordinates.
+    //column layouts are the same thing mirrored across the diagonal, which takes
+    //corner i of a cell to corner 4-i.
+    //corner positions relative to the doubled coordinate of a cell
+    fn lattice_corners(layout: OffsetLayout) -> [IVec2; 6] {
+        const ROW_CORNERS: [IVec2; 6] = [
+            IVec2::new(1, 1),
+            IVec2::new(0, 1),
+            IVec2::new(0, 0),
+            IVec2::new(1, 0),
+            IVec2::new(2, 0),
+            IVec2::new(2, 1),
+        ];
+        if layout.is_rows() {
+            ROW_CORNERS
+        } else {
+            std::array::from_fn(|i| ROW_CORNERS[(10 - i)%6].yx())
+        }
+    }
+
+    fn lattice_refs(hex: HexCoordinate, layout: OffsetLayout) -> [IVec2; 6] {
+        let doubled = DoubledCoordinate::from_hex(hex, layout);
+        let base = IVec2::new(doubled.x, doubled.z);
+        Self::lattice_corners(layout).map(|c| base + c)
+    }
+
+    fn create_cell(hex: HexCoordinate, height_refs: DirectionArray<HexCorner>, layout: &HexLayout) -> HexCell {
+        let position = hex.position(layout);
+        let neighbor_cell_refs = DirectionArray([None; 6]);
         HexCell{
             position,
             height_refs,
-            terrain,
             neighbor_cell_refs
         }
     }
@@ -245,7 +1198,7 @@ impl HexGrid {
         x
     }
     fn calc_weight(v: Vec2, n: Vec2, m: Vec2, x: Vec2) -> f32 {
-        let x = x;
+        #[allow(clippy::excessive_precision)]
         static K: f32 = 0.86602540378;
         K/(n.dot(v-x)*m.dot(v-x))
     }
@@ -254,19 +1207,18 @@ impl HexGrid {
         let x = Vec2::new(v.x - cell.position.x, v.z - cell.position.z);
         let mut sum = 0.0;
         let mut height = 0.0;
-        for i in 0..6 {
-            let p = HEX_CORNERS[i];
+        for dir in HexDirection::all() {
+            let p = dir.first_corner(&self.layout);
             let v = Vec2::new(p.x, p.z);
-            let n = HEX_NORMALS[i];
-            let m = HEX_NORMALS[i+1];
+            let [n, m] = self.layout.corner_normals[dir.index()];
             let weight = Self::calc_weight(v, n, m, x);
 
             sum += weight;
 
-            let (height_x, height_z) = cell.height_refs[i];
-            height += self.heights[height_x][height_z] as f32 * weight;
+            let corner = cell.height_refs[dir];
+            height += self.heights[corner.x][corner.z] as f32 * weight;
         }
-        Self::curve(height/sum)*(OUTER_RADIUS/4.0)
+        Self::curve(height/sum)*self.layout.elevation_step
     }
 
     fn calc_normal(&self, v: Vec3, height: f32, cell: &HexCell) -> Vec3 {
@@ -276,25 +1228,59 @@ impl HexGrid {
         Vec3::new(dx/EPS, 1.0, dz/EPS).normalize()
     }
 
+    //how far a neighbor is from where it would be without wrapping, zero away from the seam
+    fn seam_shift(&self, cell: &HexCell, dir: HexDirection, neighbor: &HexCell) -> Vec3 {
+        neighbor.position - (cell.position + dir.vector(&self.layout))
+    }
+
     fn calc_height_and_normal(&self, x: Vec3, cell: &HexCell) -> (f32, Vec3) {
         let height = self.calc_height(x, cell);
         (height, self.calc_normal(x, height, cell))
     }
 
-    pub fn triangulate_grid(&self) -> Mesh {
-        let mut data = HexMeshData {
-            vertices: vec![],
-            colors: vec![],
-            vert_terrain: vec![],
-            triangles: vec![],
-            normals: vec![]
-        };
-        for cell in self.cells.iter().flatten() {
-            self.triangulate_cell(
-                cell,
-                &mut data
-            );
-        }
+    //`subdivisions` is in 1..=MAX_SUBDIVISIONS, see DEFAULT_SUBDIVISIONS
+    pub fn triangulate_grid(&self, registry: &TerrainRegistry, subdivisions: u32) -> Mesh {
+        self.triangulate_cells(self.iter_cells(), registry, &|_| subdivisions)
+    }
+
+    //the cells of one chunk, see chunk_count
+    pub fn triangulate_chunk(
+        &self,
+        chunk: UVec2,
+        chunk_size: usize,
+        registry: &TerrainRegistry,
+        subdivisions: u32
+    ) -> Mesh {
+        self.triangulate_chunk_lod(chunk, chunk_size, registry, |_| subdivisions)
+    }
+
+    //triangulate_chunk with the subdivisions of every chunk given by `chunk_subdivisions`. the connection
+    //strips into neighboring chunks are stitched to the neighbor's subdivisions, so chunks meshed with the
+    //same function meet without cracks. when a chunk's subdivisions change, its neighbors have to be meshed again.
+    pub fn triangulate_chunk_lod(
+        &self,
+        chunk: UVec2,
+        chunk_size: usize,
+        registry: &TerrainRegistry,
+        chunk_subdivisions: impl Fn(UVec2) -> u32
+    ) -> Mesh {
+        let start_x = chunk.x as usize*chunk_size;
+        let start_z = chunk.y as usize*chunk_size;
+        let cells = (start_x..start_x + chunk_size)
+            .flat_map(|x| (start_z..start_z + chunk_size).map(move |z| OffsetCoordinate::new(x, z)))
+            .filter_map(|offset| Some((offset, self.cell(offset)?)));
+        self.triangulate_cells(cells, registry, &|offset| chunk_subdivisions(Self::chunk_of(offset, chunk_size)))
+    }
+
+    fn triangulate_cells<'a>(
+        &'a self,
+        cells: impl Iterator<Item = (OffsetCoordinate, &'a HexCell)>,
+        registry: &TerrainRegistry,
+        subdivisions: &dyn Fn(OffsetCoordinate) -> u32
+    ) -> Mesh {
+        let mut data = self.mesh_data(cells, registry, subdivisions);
+        data.weld();
+        let inner_radius = self.layout.inner_radius();
         Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD)
             .with_inserted_attribute(
                 Mesh::ATTRIBUTE_POSITION,
@@ -302,7 +1288,7 @@ impl HexGrid {
             )
             .with_inserted_attribute(
                 Mesh::ATTRIBUTE_UV_0,
-                data.vertices.iter().map(|v| Vec2::new(v.x/INNER_RADIUS, v.z/INNER_RADIUS)).collect::<Vec<Vec2>>()
+                data.vertices.iter().map(|v| Vec2::new(v.x/inner_radius, v.z/inner_radius)).collect::<Vec<Vec2>>()
             )
             .with_inserted_attribute(
                 Mesh::ATTRIBUTE_COLOR,
@@ -323,19 +1309,52 @@ impl HexGrid {
             //.with_computed_smooth_normals()
     }
 
+    //unwelded, `subdivisions` is the subdivisions of each cell
+    fn mesh_data<'a>(
+        &'a self,
+        cells: impl Iterator<Item = (OffsetCoordinate, &'a HexCell)>,
+        registry: &TerrainRegistry,
+        subdivisions: &dyn Fn(OffsetCoordinate) -> u32
+    ) -> HexMeshData {
+        let subdivisions = |offset| {
+            let subdivisions = subdivisions(offset);
+            assert!(
+                (1..=MAX_SUBDIVISIONS).contains(&subdivisions),
+                "subdivisions must be in 1..={}", MAX_SUBDIVISIONS
+            );
+            subdivisions
+        };
+        let mut data = HexMeshData::default();
+        for (offset, cell) in cells {
+            self.triangulate_cell(
+                offset,
+                cell,
+                registry,
+                &subdivisions,
+                &mut data
+            );
+        }
+        data
+    }
+
     fn triangulate_cell(
         &self,
+        offset: OffsetCoordinate,
         cell: &HexCell,
+        registry: &TerrainRegistry,
+        subdivisions: &dyn Fn(OffsetCoordinate) -> u32,
         data: &mut HexMeshData
     ) {
-        for dir in NE..=NW {
-            //this whole process is creating redundant verts and should probably be changed.
+        let terrain = registry.texture_layer(self.terrain[offset]);
+        let cell_subdivisions = subdivisions(offset);
+        for dir in HexDirection::all() {
             let vert_idx_pre_tri = data.vertices.len();
             self.subdivide_triangle(
                 cell.position,
-                cell.position + HEX_CORNERS[dir]*SOLID_FACTOR,
-                 cell.position + HEX_CORNERS[dir+1]*SOLID_FACTOR,
-                cell.terrain,
+                cell.position + dir.first_corner(&self.layout)*self.layout.solid_factor,
+                cell.position + dir.second_corner(&self.layout)*self.layout.solid_factor,
+                terrain,
+                cell_subdivisions,
                 data
             );
             for vertex in &mut data.vertices[vert_idx_pre_tri..] {
@@ -343,154 +1362,146 @@ impl HexGrid {
                 vertex.y = h;
                 data.normals.push(n);
             }
-            if dir <= SE {
+            if dir <= HexDirection::SouthWest {
                 let e = EdgeVertices::new(
-                    cell.position + HEX_CORNERS[dir]*SOLID_FACTOR,
-                    cell.position + HEX_CORNERS[dir+1]*SOLID_FACTOR,
+                    cell.position + dir.first_corner(&self.layout)*self.layout.solid_factor,
+                    cell.position + dir.second_corner(&self.layout)*self.layout.solid_factor,
+                    cell_subdivisions
                 );
                 self.triangulate_connection(
                     dir,
                     cell,
+                    terrain,
+                    registry,
                     e,
+                    subdivisions,
                     data
                 );
             }
         }
     }
 
+    //v1 is the center of the cell. rows of points run parallel to the v2-v3 edge, the last row is the
+    //same points as EdgeVertices::new(v2, v3, subdivisions) so the connection strip meets it exactly.
     fn subdivide_triangle(
         &self,
         v1: Vec3,
         v2: Vec3,
         v3: Vec3,
         terrain: u32,
+        subdivisions: u32,
         data: &mut HexMeshData
     ) {
-        let va1 = v1.lerp(v2, 1.0/3.0);
-        let va2 = v1.lerp(v2, 2.0/3.0);
-
-        let vb1 = v2.lerp(v3, 1.0/3.0);
-        let vb2 = v2.lerp(v3, 2.0/3.0);
-
-        let vc1 = v3.lerp(v1, 1.0/3.0);
-        let vc2 = v3.lerp(v1, 2.0/3.0);
-
-        let c = (v1 + v2 + v3)/3.0;
-
+        let n = subdivisions;
         let vert_idx = data.vertices.len() as u32;
+        //index of the j-th point of row i, row i has i + 1 points
+        let idx = |i: u32, j: u32| vert_idx + i*(i + 1)/2 + j;
 
-        data.vertices.push(c  ); //+0
-        data.vertices.push(v1 ); //+1
-        data.vertices.push(v2 ); //+2
-        data.vertices.push(v3 ); //+3
-        data.vertices.push(va1); //+4
-        data.vertices.push(va2); //+5
-        data.vertices.push(vb1); //+6
-        data.vertices.push(vb2); //+7
-        data.vertices.push(vc1); //+8
-        data.vertices.push(vc2); //+9
-
-        data.colors.append(&mut vec![COLOR1; 10]);
-        data.vert_terrain.append(&mut vec![UVec3::new(terrain, terrain, terrain); 10]);
-
-        data.triangles.append(&mut vec![vert_idx+1, vert_idx+9, vert_idx+4]);
-
-        data.triangles.append(&mut vec![vert_idx+5, vert_idx+4, vert_idx ]);
-
-        data.triangles.append(&mut vec![vert_idx+5, vert_idx+6, vert_idx+2]);
-
-        data.triangles.append(&mut vec![vert_idx  , vert_idx+7, vert_idx+6]);
-
-        data.triangles.append(&mut vec![vert_idx+8, vert_idx+3, vert_idx+7]);
-
-        data.triangles.append(&mut vec![vert_idx+9, vert_idx+8, vert_idx  ]);
-
-        data.triangles.append(&mut vec![vert_idx  , vert_idx+4, vert_idx+9]);
-
-        data.triangles.append(&mut vec![vert_idx+5, vert_idx  , vert_idx+6]);
+        data.vertices.push(v1);
+        for i in 1..n {
+            let t = i as f32/n as f32;
+            data.vertices.extend(EdgeVertices::new(v1.lerp(v2, t), v1.lerp(v3, t), i).0);
+        }
+        data.vertices.extend(EdgeVertices::new(v2, v3, n).0);
+        let count = ((n + 1)*(n + 2)/2) as usize;
+        data.colors.extend(std::iter::repeat_n(COLOR1, count));
+        data.vert_terrain.extend(std::iter::repeat_n(UVec3::new(terrain, terrain, terrain), count));
 
-        data.triangles.append(&mut vec![vert_idx+8, vert_idx+7, vert_idx  ]);
+        for i in 0..n {
+            for j in 0..=i {
+                data.triangles.extend([idx(i, j), idx(i + 1, j + 1), idx(i + 1, j)]);
+                if j < i {
+                    data.triangles.extend([idx(i, j), idx(i, j + 1), idx(i + 1, j + 1)]);
+                }
+            }
+        }
     }
 
+    //e1 has the subdivisions of `cell`, the other side of the strip gets the neighbor's from `subdivisions`
+    #[allow(clippy::too_many_arguments)]
     fn triangulate_connection(
         &self,
-        dir: usize,
+        dir: HexDirection,
         cell: &HexCell,
+        terrain: u32,
+        registry: &TerrainRegistry,
         e1: EdgeVertices,
+        subdivisions: &dyn Fn(OffsetCoordinate) -> u32,
         data: &mut HexMeshData
     ) {
-        match cell.neighbor_cell_refs[dir] {
-            Some((x, z)) => {
-                let neighbor = &self.cells[x][z];
-                //let bridge = (HEX_CORNERS[dir] + HEX_CORNERS[dir+1])*BLEND_FACTOR;
-                let mut bridge = (HEX_CORNERS[dir] + HEX_CORNERS[dir+1])*BLEND_FACTOR;
-                bridge.y = neighbor.position.y - cell.position.y;
-                let e2 = EdgeVertices::new(
-                    e1.v1 + bridge,
-                    e1.v4 + bridge
-                );
-                self.triangulate_edge_strip(
-                    &e1,
-                    cell.terrain,
-                    &e2,
-                    neighbor.terrain,
-                    data
-                );
-                let vert_idx = data.vertices.len();
-                for (idx, vertex) in &mut data.vertices[(vert_idx-12)..].iter_mut().enumerate() {
-                    if idx%4 < 2 {
-                        let (h, n) = self.calc_height_and_normal(*vertex, cell);
-                        vertex.y = h;
-                        data.normals.push(n);
-                    } else {
-                        let (h, n) = self.calc_height_and_normal(*vertex, neighbor);
-                        vertex.y = h;
-                        data.normals.push(n);
-                    }
+        if let Some((x, z)) = cell.neighbor_cell_refs[dir] {
+            let neighbor = self.cells[x][z].as_ref().unwrap();
+            let neighbor_terrain = registry.texture_layer(self.terrain[OffsetCoordinate::new(x, z)]);
+            let shift = self.seam_shift(cell, dir, neighbor);
+            let mut bridge = dir.vector(&self.layout)*self.layout.blend_factor();
+            bridge.y = neighbor.position.y - cell.position.y;
+            let e2 = EdgeVertices::new(
+                e1.first() + bridge,
+                e1.last() + bridge,
+                subdivisions(OffsetCoordinate::new(x, z))
+            );
+            let vert_idx = data.vertices.len();
+            self.triangulate_edge_strip(
+                &e1,
+                terrain,
+                &e2,
+                neighbor_terrain,
+                data
+            );
+            for (idx, vertex) in data.vertices[vert_idx..].iter_mut().enumerate() {
+                if idx < e1.0.len() {
+                    let (h, n) = self.calc_height_and_normal(*vertex, cell);
+                    vertex.y = h;
+                    data.normals.push(n);
+                } else {
+                    let (h, n) = self.calc_height_and_normal(*vertex + shift, neighbor);
+                    vertex.y = h;
+                    data.normals.push(n);
                 }
-                //TODO - this triangle is getting made more times than it needs to be. Investigate.
-                if dir <= E {
-                    if let Some((x, z)) = cell.neighbor_cell_refs[(dir+1)%6] {
-                        let next_dir = (dir+1)%6;
-                        let next_neighbor = &self.cells[x][z];
-                        let bridge = (HEX_CORNERS[next_dir] + HEX_CORNERS[next_dir+1])*BLEND_FACTOR;
-                        let vert_idx = data.vertices.len();
-
-                        [e1.v4, e2.v4, e1.v4.clone() + bridge]
-                            .into_iter()
-                            .zip(
-                                [cell, neighbor, next_neighbor]
-                                    .into_iter()
-                            )
-                            .for_each(|(v, c)| {
-                                let (h, n) = self.calc_height_and_normal(v, c);
-                                data.vertices.push(Vec3::new(v.x, h, v.z));
-                                data.normals.push(n);
-                            });
-
-                        //data
-
-                        let vert_idx = vert_idx as u32;
-                        data.triangles.append(&mut vec![vert_idx, vert_idx + 2, vert_idx + 1]);
-                        data.colors.append(&mut vec![
-                            COLOR2,
-                            COLOR1,
-                            COLOR3
-                        ]);
-                        let types = UVec3::new(
-                            neighbor.terrain,
-                            cell.terrain,
-                            next_neighbor.terrain
-                        );
-                        data.vert_terrain.append(&mut vec![types; 3]);
+            }
+            //TODO - this triangle is getting made more times than it needs to be. Investigate.
+            if dir <= HexDirection::East {
+                if let Some((x, z)) = cell.neighbor_cell_refs[dir.next()] {
+                    let next_neighbor = self.cells[x][z].as_ref().unwrap();
+                    let next_terrain = registry.texture_layer(self.terrain[OffsetCoordinate::new(x, z)]);
+                    let next_shift = self.seam_shift(cell, dir.next(), next_neighbor);
+                    let bridge = dir.next().vector(&self.layout)*self.layout.blend_factor();
+                    let vert_idx = data.vertices.len();
+
+                    [e1.last(), e2.last(), e1.last() + bridge]
+                        .into_iter()
+                        .zip(
+                            [(cell, Vec3::ZERO), (neighbor, shift), (next_neighbor, next_shift)]
+                        )
+                        .for_each(|(v, (c, shift))| {
+                            let (h, n) = self.calc_height_and_normal(v + shift, c);
+                            data.vertices.push(Vec3::new(v.x, h, v.z));
+                            data.normals.push(n);
+                        });
+
+                    //data
+
+                    let vert_idx = vert_idx as u32;
+                    data.triangles.append(&mut vec![vert_idx, vert_idx + 2, vert_idx + 1]);
+                    data.colors.append(&mut vec![
+                        COLOR2,
+                        COLOR1,
+                        COLOR3
+                    ]);
+                    let types = UVec3::new(
+                        neighbor_terrain,
+                        terrain,
+                        next_terrain
+                    );
+                    data.vert_terrain.append(&mut vec![types; 3]);
 
-                    }
                 }
-            },
-            None => {return}
+            }
         }
     }
 
+    //the points of e1 followed by the points of e2. when they have different subdivisions, the triangles
+    //between them fan out from whichever side is behind along the strip.
     fn triangulate_edge_strip(
         &self,
         e1: &EdgeVertices,
@@ -499,34 +1510,535 @@ impl HexGrid {
         terrain2: u32,
         data: &mut HexMeshData
     ) {
-        //TODO: remove redundant verts
-        let terrain = UVec3::new(terrain2, terrain1, terrain2);
-        Self::add_quad(e1.v1, e1.v2, e2.v1, e2.v2, data);
-        data.colors.append(&mut vec![COLOR2, COLOR2, COLOR1, COLOR1]);
-        Self::add_quad(e1.v2, e1.v3, e2.v2, e2.v3, data);
-        data.colors.append(&mut vec![COLOR2, COLOR2, COLOR1, COLOR1]);
-        Self::add_quad(e1.v3, e1.v4, e2.v3, e2.v4, data);
-        data.colors.append(&mut vec![COLOR2, COLOR2, COLOR1, COLOR1]);
-        data.vert_terrain.append(&mut vec![terrain; 12]);
+        let vert_idx = data.vertices.len() as u32;
+        let (n, m) = (e1.0.len() as u32 - 1, e2.0.len() as u32 - 1);
+        data.vertices.extend(e1.0.iter().chain(&e2.0));
+        data.colors.extend(std::iter::repeat_n(COLOR2, e1.0.len()).chain(std::iter::repeat_n(COLOR1, e2.0.len())));
+        data.vert_terrain.extend(std::iter::repeat_n(UVec3::new(terrain2, terrain1, terrain2), e1.0.len() + e2.0.len()));
 
+        let (a, b) = (|i: u32| vert_idx + i, |j: u32| vert_idx + n + 1 + j);
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            //step along e1 if its next point comes first, (i + 1)/n <= (j + 1)/m
+            if i < n && (j == m || (i + 1)*m <= (j + 1)*n) {
+                data.triangles.extend([a(i), a(i + 1), b(j)]);
+                i += 1;
+            } else {
+                data.triangles.extend([a(i), b(j + 1), b(j)]);
+                j += 1;
+            }
+        }
     }
+}
 
-    fn add_quad(
-        v1: Vec3,
-        v2: Vec3,
-        v3: Vec3,
-        v4: Vec3,
-        data: &mut HexMeshData
-    ) {
-        let vert_idx = data.vertices.len() as u32;
-        data.vertices.append(&mut vec![v1, v2, v3, v4]);
-        data.triangles.append(&mut vec![
-            vert_idx,
-            vert_idx + 1,
-            vert_idx + 2,
-            vert_idx + 1,
-            vert_idx + 3,
-            vert_idx + 2
-        ])
-    }
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use bevy::math::Vec3Swizzles;
+    use bevy::prelude::Handle;
+    use crate::terrain::TerrainType;
+    use super::*;
+
+    fn registry() -> TerrainRegistry {
+        TerrainRegistry {
+            texture: String::new(),
+            layers: 4,
+            types: (0..4)
+                .map(|layer| TerrainType {
+                    name: layer.to_string(),
+                    layer,
+                    movement_cost: 1.0,
+                    color: [1.0; 4],
+                    tags: vec![]
+                })
+                .collect(),
+            texture_handle: Handle::default()
+        }
+    }
+
+    #[test]
+    fn cube_coordinates() {
+        let a = HexCoordinate::new(2, -5);
+        let b = HexCoordinate::new(-1, 3);
+        for hex in [a, b, a + b, a - b, a*3, -a] {
+            assert_eq!(hex.x() + hex.y() + hex.z(), 0);
+        }
+        assert_eq!(a + b, HexCoordinate::new(1, -2));
+        assert_eq!(a - b, HexCoordinate::new(3, -8));
+        assert_eq!(a*3, HexCoordinate::new(6, -15));
+        assert_eq!(a - a, HexCoordinate::ZERO);
+        assert_eq!(a.distance(b), 8);
+        for (dir, &neighbor) in a.neighbors().iter() {
+            assert_eq!(neighbor, a.neighbor(dir));
+            assert_eq!(a.distance(neighbor), 1);
+            assert_eq!(neighbor.neighbor(dir.opposite()), a);
+        }
+    }
+
+    #[test]
+    fn ring_spiral_and_range() {
+        let center = HexCoordinate::new(3, -1);
+        for radius in 0..5 {
+            let ring = center.ring(radius).collect::<Vec<HexCoordinate>>();
+            assert_eq!(ring.len(), if radius == 0 { 1 } else { 6*radius as usize });
+            assert!(ring.iter().all(|hex| hex.distance(center) == radius as i32));
+            assert_eq!(ring.iter().collect::<HashSet<_>>().len(), ring.len());
+
+            let range = center.range(radius).collect::<HashSet<HexCoordinate>>();
+            assert_eq!(range.len(), (3*radius*(radius + 1) + 1) as usize);
+            assert!(range.iter().all(|hex| hex.distance(center) <= radius as i32));
+            assert_eq!(center.spiral(radius).collect::<HashSet<HexCoordinate>>(), range);
+        }
+    }
+
+    #[test]
+    fn lines() {
+        let cases = [
+            (HexCoordinate::ZERO, HexCoordinate::ZERO),
+            (HexCoordinate::ZERO, HexCoordinate::new(5, 0)),
+            (HexCoordinate::new(-2, 4), HexCoordinate::new(3, -3)),
+            (HexCoordinate::new(1, 1), HexCoordinate::new(-4, 2)),
+        ];
+        for (a, b) in cases {
+            let line = a.line_to(b).collect::<Vec<HexCoordinate>>();
+            assert_eq!(line.len(), a.distance(b) as usize + 1);
+            assert_eq!((line[0], line[line.len() - 1]), (a, b));
+            assert!(line.windows(2).all(|w| w[0].distance(w[1]) == 1), "{:?}", line);
+            assert_eq!(a.lerp(b, 0.0), a);
+            assert_eq!(a.lerp(b, 1.0), b);
+        }
+    }
+
+    #[test]
+    fn line_of_sight_is_blocked_by_high_cells() {
+        let mut grid = HexGrid::with_layout(7, 3, HexLayout::default());
+        let (from, to) = (OffsetCoordinate::new(0, 1), OffsetCoordinate::new(6, 1));
+        assert!(grid.line_of_sight(from, to, 1.0));
+        for corner in grid.cell_corners(OffsetCoordinate::new(3, 1)).unwrap().0 {
+            grid.set_height_at_corner(corner, 20);
+        }
+        assert!(!grid.line_of_sight(from, to, 1.0));
+        assert!(grid.line_of_sight(from, to, 100.0));
+        assert!(!grid.line_of_sight(from, OffsetCoordinate::new(7, 1), 1.0));
+
+        //the line from (0, 0) to (0, 2) runs along the seam through a copy of (5, 1), whose far side is high
+        let mut grid = HexGrid::with_layout(6, 5, HexLayout::default().with_wrap_x(true));
+        for column in &mut grid.heights {
+            column.fill(0);
+        }
+        let (from, to) = (OffsetCoordinate::new(0, 0), OffsetCoordinate::new(0, 2));
+        let seam = [from, OffsetCoordinate::new(0, 1), to]
+            .into_iter()
+            .flat_map(|offset| grid.cell_corners(offset).unwrap().0)
+            .collect::<Vec<HexCorner>>();
+        for corner in grid.cell_corners(OffsetCoordinate::new(5, 1)).unwrap().0 {
+            if !seam.contains(&corner) {
+                grid.set_height_at_corner(corner, 20);
+            }
+        }
+        let (start, end) = (grid.cell(from).unwrap().position, grid.cell(to).unwrap().position);
+        for i in 0..=8 {
+            assert!(grid.height_at(start.lerp(end, i as f32/8.0)).unwrap() < 0.5);
+        }
+        assert!(grid.line_of_sight(from, to, 1.0));
+    }
+
+    #[test]
+    fn layout_builders_keep_other_fields() {
+        let fields = |l: HexLayout| (
+            l.orientation(),
+            l.offset_layout(),
+            l.wraps_x(),
+            l.outer_radius(),
+            l.solid_factor(),
+            l.elevation_step()
+        );
+        let base = HexLayout::new(HexOrientation::FlatTop)
+            .with_offset_layout(OffsetLayout::EvenQ)
+            .with_wrap_x(true)
+            .with_solid_factor(0.7)
+            .with_elevation_step(3.0);
+        let expected = (HexOrientation::FlatTop, OffsetLayout::EvenQ, true, OUTER_RADIUS, 0.7, 3.0);
+        assert_eq!(fields(base), expected);
+
+        let mut changed = [expected; 5];
+        changed[0].1 = OffsetLayout::OddR;
+        changed[1].2 = false;
+        changed[2].3 = 4.0;
+        changed[3].4 = 0.5;
+        changed[4].5 = 1.0;
+        let layouts = [
+            base.with_offset_layout(OffsetLayout::OddR),
+            base.with_wrap_x(false),
+            base.with_outer_radius(4.0),
+            base.with_solid_factor(0.5),
+            base.with_elevation_step(1.0),
+        ];
+        for (layout, expected) in layouts.into_iter().zip(changed) {
+            assert_eq!(fields(layout), expected);
+        }
+        assert_eq!(base.with_outer_radius(4.0).with_outer_radius(OUTER_RADIUS), base);
+    }
+
+    const OFFSET_LAYOUTS: [OffsetLayout; 4] = [OffsetLayout::OddR, OffsetLayout::EvenR, OffsetLayout::OddQ, OffsetLayout::EvenQ];
+
+    #[test]
+    fn offset_and_doubled_round_trips() {
+        for layout in OFFSET_LAYOUTS {
+            for x in 0..6 {
+                for z in 0..6 {
+                    let offset = OffsetCoordinate::new(x, z);
+                    let hex = HexCoordinate::from_offset(offset, layout);
+                    assert_eq!(OffsetCoordinate::from_hex(hex, layout), Some(offset));
+                }
+            }
+            for hex in HexCoordinate::ZERO.range(4) {
+                let doubled = DoubledCoordinate::from_hex(hex, layout);
+                assert_eq!((doubled.x + doubled.z)%2, 0);
+                assert_eq!(doubled.to_hex(layout), Some(hex));
+            }
+            assert_eq!(DoubledCoordinate{x: 1, z: 0}.to_hex(layout), None);
+            assert_eq!(OffsetCoordinate::from_hex(HexCoordinate::new(0, -1), layout), None);
+        }
+        //where the second row (column) starts depends on which rows (columns) are shifted
+        let second = |layout: OffsetLayout| {
+            let offset = if layout.is_rows() { OffsetCoordinate::new(0, 1) } else { OffsetCoordinate::new(1, 0) };
+            HexCoordinate::from_offset(offset, layout)
+        };
+        assert_eq!(second(OffsetLayout::OddR), HexCoordinate::new(0, 1));
+        assert_eq!(second(OffsetLayout::EvenR), HexCoordinate::new(-1, 1));
+        assert_eq!(second(OffsetLayout::OddQ), HexCoordinate::new(1, 0));
+        assert_eq!(second(OffsetLayout::EvenQ), HexCoordinate::new(1, -1));
+    }
+
+    #[test]
+    fn shape_constructors() {
+        for offset in OFFSET_LAYOUTS {
+            let layout = HexLayout::default().with_offset_layout(offset);
+            let shapes = [
+                (HexGrid::hexagon(3, layout), HexCoordinate::ZERO.range(3).collect::<Vec<HexCoordinate>>()),
+                (
+                    HexGrid::parallelogram(4, 3, layout),
+                    (0..4).flat_map(|x| (0..3).map(move |z| HexCoordinate::new(x, z))).collect()
+                ),
+                (
+                    HexGrid::triangle(4, layout),
+                    (0..4).flat_map(|x| (0..4 - x).map(move |z| HexCoordinate::new(x, z))).collect()
+                ),
+            ];
+            for (grid, mut expected) in shapes {
+                //the grid has the same hexes, moved so they fit in its cells
+                let mut hexes = grid.iter_cells()
+                    .map(|(offset, _)| grid.hex_coordinate(offset))
+                    .collect::<Vec<HexCoordinate>>();
+                assert_eq!(hexes.len(), expected.len());
+                let key = |hex: &HexCoordinate| (hex.x(), hex.z());
+                hexes.sort_by_key(key);
+                expected.sort_by_key(key);
+                let shift = hexes[0] - expected[0];
+                assert!(hexes.iter().zip(&expected).all(|(&a, &b)| a - b == shift), "{:?}", offset);
+            }
+        }
+        let mask = vec![vec![true, false, true], vec![false, true, true]];
+        let grid = HexGrid::from_mask(&mask, HexLayout::default());
+        assert_eq!(grid.iter_cells().count(), 4);
+        assert!(!grid.contains(OffsetCoordinate::new(0, 1)));
+        assert!(grid.contains(OffsetCoordinate::new(1, 2)));
+        assert_eq!(HexGrid::from_hexes([], HexLayout::default()).iter_cells().count(), 0);
+    }
+
+    #[test]
+    fn positions_off_the_map_have_no_cell() {
+        let grid = HexGrid::with_layout(4, 3, HexLayout::default());
+        for (offset, cell) in grid.iter_cells() {
+            assert_eq!(grid.cell_at_position(cell.position), Some(offset));
+        }
+        //north is -z
+        let step = 2.0*OUTER_RADIUS;
+        let first = grid.cell(OffsetCoordinate::new(0, 0)).unwrap().position;
+        let last = grid.cell(OffsetCoordinate::new(3, 2)).unwrap().position;
+        for position in [
+            first - Vec3::X*step,
+            first + Vec3::Z*step,
+            last + Vec3::X*step,
+            last - Vec3::Z*step,
+            Vec3::splat(f32::MAX),
+        ] {
+            assert_eq!(grid.cell_at_position(position), None, "{position}");
+        }
+
+        let mask = vec![vec![true; 3], vec![true, false, true], vec![true; 3]];
+        let grid = HexGrid::from_mask(&mask, HexLayout::default());
+        let hole = grid.hex_coordinate(OffsetCoordinate::new(1, 1)).position(grid.layout());
+        assert_eq!(grid.cell_at_position(hole), None);
+
+        let grid = HexGrid::with_layout(4, 3, HexLayout::default().with_wrap_x(true));
+        let last = grid.cell(OffsetCoordinate::new(3, 1)).unwrap().position;
+        let east = last + Vec3::X*2.0*grid.layout().inner_radius();
+        assert_eq!(grid.cell_at_position(east), Some(OffsetCoordinate::new(0, 1)));
+        let first = grid.cell(OffsetCoordinate::new(0, 1)).unwrap().position;
+        assert_eq!(grid.cell_at_position(first + grid.wrap_offset().unwrap()), Some(OffsetCoordinate::new(0, 1)));
+        assert_eq!(grid.cell_at_position(first - Vec3::Z*4.0*step), None);
+    }
+
+    #[test]
+    fn only_straight_rows_and_columns_wrap() {
+        for orientation in [HexOrientation::PointyTop, HexOrientation::FlatTop] {
+            for offset in OFFSET_LAYOUTS {
+                let layout = HexLayout::new(orientation).with_offset_layout(offset);
+                let straight = offset.is_rows() == (orientation == HexOrientation::PointyTop);
+                assert_eq!(layout.can_wrap_x(), straight);
+                if straight {
+                    let wrap_offset = HexGrid::with_layout(6, 6, layout.with_wrap_x(true)).wrap_offset().unwrap();
+                    assert!(wrap_offset.x > 0.0 && wrap_offset.z.abs() < 1e-3, "{orientation:?} {offset:?}");
+                } else {
+                    let wrapped = std::panic::catch_unwind(|| HexGrid::with_layout(6, 6, layout.with_wrap_x(true)));
+                    assert!(wrapped.is_err(), "{orientation:?} {offset:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn corners_are_shared_by_neighbors() {
+        for offset in OFFSET_LAYOUTS {
+            for wrap_x in [false, true] {
+                let orientation = if offset.is_rows() { HexOrientation::PointyTop } else { HexOrientation::FlatTop };
+                let layout = HexLayout::new(orientation).with_offset_layout(offset).with_wrap_x(wrap_x);
+                let grid = HexGrid::with_layout(6, 5, layout);
+                for (cell_offset, cell) in grid.iter_cells() {
+                    for dir in HexDirection::all() {
+                        let corner = cell.height_refs[dir];
+                        let cells = grid.corner_cells(corner).collect::<Vec<OffsetCoordinate>>();
+                        assert!(cells.contains(&cell_offset) && cells.len() <= 3);
+                        let neighbor = grid.hex_coordinate(cell_offset).neighbor(dir);
+                        let Some(neighbor) = grid.offset_coordinate(neighbor) else {
+                            continue;
+                        };
+                        let neighbor_corners = grid.cell_corners(neighbor).unwrap().0;
+                        assert!(neighbor_corners.contains(&corner));
+                        assert!(neighbor_corners.contains(&cell.height_refs[dir.next()]));
+                    }
+                }
+                let inner = grid.cell_corners(OffsetCoordinate::new(2, 2)).unwrap();
+                assert!(inner.0.iter().all(|&corner| grid.corner_cells(corner).count() == 3));
+            }
+        }
+    }
+
+    #[test]
+    fn edges_are_the_same_from_both_sides() {
+        let hex = HexCoordinate::new(2, -1);
+        for dir in HexDirection::all() {
+            let edge = HexEdge::new(hex, dir);
+            assert_eq!(edge, HexEdge::new(hex.neighbor(dir), dir.opposite()));
+            assert!(edge.direction() <= HexDirection::SouthWest);
+            assert!(edge.cells().contains(&hex) && edge.cells().contains(&hex.neighbor(dir)));
+        }
+
+        //across the seam of a wrapping map, the two copies of an edge hold the same data
+        let mut grid = HexGrid::with_layout(4, 3, HexLayout::default().with_wrap_x(true));
+        let west = grid.edge(OffsetCoordinate::new(0, 1), HexDirection::West);
+        let east = grid.edge(OffsetCoordinate::new(3, 1), HexDirection::East);
+        assert_ne!(west, east);
+        assert_eq!(grid.canonical_edge(west), grid.canonical_edge(east));
+        let data = HexEdgeData{river: true, ..HexEdgeData::default()};
+        assert!(grid.set_edge_data(east, data));
+        assert_eq!(grid.edge_data(west), data);
+        assert_eq!(grid.edges.len(), 1);
+
+        let outside = HexEdge::new(HexCoordinate::new(0, -5), HexDirection::West);
+        assert_eq!(grid.canonical_edge(outside), None);
+        assert!(!grid.set_edge_data(outside, data));
+    }
+
+    //every triangle of the welded mesh has to have the same corners as before welding, up to WELD_PRECISION
+    #[test]
+    fn welding_keeps_triangles() {
+        let registry = registry();
+        let layouts = [
+            HexLayout::new(HexOrientation::PointyTop),
+            HexLayout::new(HexOrientation::FlatTop).with_wrap_x(true),
+            HexLayout::new(HexOrientation::FlatTop).with_offset_layout(OffsetLayout::EvenQ).with_wrap_x(true),
+        ];
+        for layout in layouts {
+            let mut grid = HexGrid::with_layout(12, 10, layout);
+            grid.randomize_terrain(4, &mut HexGrid::rng(7));
+            let mut rng = HexGrid::rng(8);
+            for column in &mut grid.heights {
+                for height in column {
+                    *height = rng.gen_range(-2..4);
+                }
+            }
+            let data = grid.mesh_data(grid.iter_cells(), &registry, &|_| DEFAULT_SUBDIVISIONS);
+            let mut welded = data.clone();
+            welded.weld();
+
+            assert_eq!(welded.triangles.len(), data.triangles.len());
+            for (&i, &j) in data.triangles.iter().zip(&welded.triangles) {
+                let (i, j) = (i as usize, j as usize);
+                assert!(data.vertices[i].abs_diff_eq(welded.vertices[j], 1.0/WELD_PRECISION));
+                assert!(data.normals[i].abs_diff_eq(welded.normals[j], 1.0/WELD_PRECISION));
+                assert_eq!(data.colors[i], welded.colors[j]);
+                assert_eq!(data.vert_terrain[i], welded.vert_terrain[j]);
+            }
+            assert!(welded.vertices.len() < data.vertices.len());
+        }
+
+        //on flat ground with one terrain, the corners of a cell and the connection strip its neighbor makes
+        //into it end up as one vertex. the corner triangles there blend differently and stay apart, and the
+        //cells' y isn't the ground's, so only x and z are compared
+        let grid = HexGrid::with_layout(5, 5, HexLayout::default());
+        let a = OffsetCoordinate::new(2, 2);
+        let dir = HexDirection::West;
+        let b = grid.offset_coordinate(grid.hex_coordinate(a).neighbor(dir)).unwrap();
+        let mesh = |cells: &[OffsetCoordinate]| {
+            let cells = grid.iter_cells().filter(|(offset, _)| cells.contains(offset));
+            let mut data = grid.mesh_data(cells, &registry, &|_| DEFAULT_SUBDIVISIONS);
+            data.weld();
+            data
+        };
+        let (a_data, b_data, welded) = (mesh(&[a]), mesh(&[b]), mesh(&[a, b]));
+        let layout = grid.layout();
+        let b_position = grid.cell(b).unwrap().position;
+        for corner in [dir.opposite().first_corner(layout), dir.opposite().second_corner(layout)] {
+            let corner = b_position + corner*layout.solid_factor;
+            let at_corner = |data: &HexMeshData| data.vertices.iter()
+                .zip(&data.colors)
+                .filter(|(v, &color)| v.xz().abs_diff_eq(corner.xz(), 1.0/WELD_PRECISION) && color == COLOR1)
+                .count();
+            assert_eq!(at_corner(&a_data), 1);
+            assert_eq!(at_corner(&b_data), 1);
+            assert_eq!(at_corner(&welded), 1);
+        }
+    }
+
+    //a T-junction leaves an edge with one triangle that has another vertex part way along it
+    fn assert_no_t_junctions(data: &HexMeshData) {
+        let mut edges = HashMap::<(u32, u32), u32>::new();
+        for triangle in data.triangles.chunks_exact(3) {
+            for k in 0..3 {
+                let (a, b) = (triangle[k], triangle[(k + 1)%3]);
+                *edges.entry((a.min(b), a.max(b))).or_default() += 1;
+            }
+        }
+        for (&(a, b), _) in edges.iter().filter(|(_, &count)| count == 1) {
+            let (a, b) = (data.vertices[a as usize], data.vertices[b as usize]);
+            for &v in &data.vertices {
+                let t = (v - a).dot(b - a)/(b - a).length_squared();
+                let on_edge = t > 0.01 && t < 0.99 && v.distance(a.lerp(b, t)) < 1.0/WELD_PRECISION;
+                assert!(!on_edge, "{} lies on the edge from {} to {}", v, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn subdivisions_leave_no_t_junctions() {
+        let registry = registry();
+        for subdivisions in [1, 2, DEFAULT_SUBDIVISIONS, MAX_SUBDIVISIONS] {
+            let mut grid = HexGrid::with_layout(4, 4, HexLayout::default());
+            grid.randomize_terrain(4, &mut HexGrid::rng(3));
+            let mut data = grid.mesh_data(grid.iter_cells(), &registry, &|_| subdivisions);
+            data.weld();
+            assert_no_t_junctions(&data);
+        }
+    }
+
+    //what triangulate_chunk_lod gives every chunk of a map, with each chunk at a different level
+    #[test]
+    fn chunks_with_different_subdivisions_meet() {
+        let registry = registry();
+        let layouts = [
+            HexLayout::new(HexOrientation::PointyTop).with_wrap_x(true),
+            HexLayout::new(HexOrientation::FlatTop),
+        ];
+        for layout in layouts {
+            let mut grid = HexGrid::with_layout(6, 6, layout);
+            grid.randomize_terrain(4, &mut HexGrid::rng(5));
+            let levels = [1, 4, 2, 3, 1, 2, 4, 3, 2];
+            let subdivisions = |offset| {
+                let chunk = HexGrid::chunk_of(offset, 2);
+                levels[(chunk.x*3 + chunk.y) as usize]
+            };
+            let mut data = grid.mesh_data(grid.iter_cells(), &registry, &subdivisions);
+            data.weld();
+            assert_no_t_junctions(&data);
+        }
+    }
+
+    //the unwelded mesh data of a chunk, to see which chunks an edit really changed
+    fn chunk_data(
+        grid: &HexGrid,
+        registry: &TerrainRegistry,
+        chunk: UVec2,
+        chunk_size: usize
+    ) -> Vec<(Vec3, Vec3, UVec3)> {
+        let cells = grid.iter_cells().filter(|(offset, _)| HexGrid::chunk_of(*offset, chunk_size) == chunk);
+        let data = grid.mesh_data(cells, registry, &|_| 2);
+        (0..data.vertices.len())
+            .map(|i| (data.vertices[i], data.normals[i], data.vert_terrain[i]))
+            .collect()
+    }
+
+    //every chunk whose mesh an edit changes has to be dirty, and the ones touching the edit are
+    #[test]
+    fn edits_dirty_the_chunks_they_change() {
+        const CHUNK_SIZE: usize = 4;
+        let registry = registry();
+        for wrap_x in [false, true] {
+            let mut grid = HexGrid::with_layout(8, 8, HexLayout::default().with_wrap_x(wrap_x));
+            grid.randomize_terrain(4, &mut HexGrid::rng(1));
+            let mut rng = HexGrid::rng(2);
+            for column in &mut grid.heights {
+                for height in column {
+                    *height = rng.gen_range(-2..4);
+                }
+            }
+            let count = grid.chunk_count(CHUNK_SIZE);
+            let chunks = (0..count.x)
+                .flat_map(|x| (0..count.y).map(move |z| UVec2::new(x, z)))
+                .collect::<Vec<UVec2>>();
+            let meshes = |grid: &HexGrid| chunks.iter()
+                .map(|&chunk| chunk_data(grid, &registry, chunk, CHUNK_SIZE))
+                .collect::<Vec<_>>();
+            let before = meshes(&grid);
+            let check = |grid: &HexGrid, generation: u64, touched: HashSet<UVec2>| {
+                let dirty = grid.dirty_chunks_since(generation, CHUNK_SIZE);
+                assert!(touched.is_subset(&dirty));
+                for (i, mesh) in meshes(grid).into_iter().enumerate() {
+                    if mesh != before[i] {
+                        assert!(dirty.contains(&chunks[i]), "{:?} changed but isn't dirty", chunks[i]);
+                    }
+                }
+                dirty
+            };
+
+            for x in 0..grid.heights.len() {
+                for z in 0..grid.heights[x].len() {
+                    let corner = HexCorner::new(x, z);
+                    let mut edited = grid.clone();
+                    let generation = edited.generation();
+                    edited.set_height_at_corner(corner, grid.heights[x][z] + 3);
+                    let touched = grid.corner_cells(corner)
+                        .map(|offset| HexGrid::chunk_of(offset, CHUNK_SIZE))
+                        .collect();
+                    check(&edited, generation, touched);
+                }
+            }
+            for (offset, &terrain) in grid.terrain.iter() {
+                let mut edited = grid.clone();
+                let generation = edited.generation();
+                edited.set_terrain(offset, (terrain + 1)%4);
+                check(&edited, generation, HashSet::from([HexGrid::chunk_of(offset, CHUNK_SIZE)]));
+            }
+
+            //(0, 2) makes the strip into its west neighbor, which is (7, 2) across the seam
+            let mut edited = grid.clone();
+            let generation = edited.generation();
+            let offset = OffsetCoordinate::new(7, 2);
+            edited.set_terrain(offset, (grid.terrain[offset] + 1)%4);
+            let dirty = check(&edited, generation, HashSet::from([UVec2::new(1, 0)]));
+            assert_eq!(dirty.contains(&UVec2::new(0, 0)), wrap_x);
+            assert!(!dirty.contains(&UVec2::new(0, 1)) && !dirty.contains(&UVec2::new(1, 1)));
+        }
+    }
+}
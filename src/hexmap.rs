@@ -0,0 +1,470 @@
+use std::sync::Arc;
+use bevy::asset::io::Reader;
+use bevy::asset::{AssetLoader, LoadContext};
+use bevy::prelude::*;
+use bevy::tasks::{block_on, AsyncComputeTaskPool, Task};
+use bevy::utils::{HashMap, HashSet};
+use crate::hexgrid::{HexGrid, DEFAULT_SUBDIVISIONS, MAX_SUBDIVISIONS};
+use crate::mapfile::MapFileError;
+use crate::terrain::TerrainRegistry;
+
+//loads .hexmap files, in either of the forms HexGrid::from_bytes reads
+#[derive(Default)]
+pub struct HexMapLoader;
+
+impl AssetLoader for HexMapLoader {
+    type Asset = HexGrid;
+    type Settings = ();
+    type Error = MapFileError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<HexGrid, MapFileError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        HexGrid::from_bytes(&bytes)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["hexmap"]
+    }
+}
+
+//cells along each side of a chunk. every chunk of a map is its own mesh, so an edit only has to
+//triangulate the chunks it touches again.
+pub const CHUNK_SIZE: usize = 16;
+
+//an entity that draws a HexGrid asset. once the grid and the TerrainRegistry resource are loaded, it is
+//given a HexMapChunk child with a Mesh3d for every chunk of the grid. when the grid changes, only the chunks
+//with cells that were edited through HexGrid's setters are meshed again, and all of them when the file
+//is reloaded or the grid was changed some other way. chunks are also meshed again when the active camera
+//moves them to another level of detail, see HexMapSettings.
+//meshing runs on the AsyncComputeTaskPool from a copy of the grid, and each chunk keeps its old mesh until
+//its new one is done. a chunk that changes again while it is being meshed drops that mesh and starts over
+//from a new copy, so an outdated mesh is never shown. the copy is only made again when the grid was edited,
+//not when chunks just move to another level of detail.
+//the material is left to the user, see inherit_hex_map_material.
+#[derive(Component, Clone, Debug)]
+#[require(Transform, Visibility)]
+pub struct HexMap(pub Handle<HexGrid>);
+
+//a child of a HexMap, with the mesh of the chunk at this position
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+#[require(Transform, Visibility)]
+pub struct HexMapChunk(pub UVec2);
+
+//how HexMap chunks are triangulated. changing it meshes every map again.
+//subdivisions outside 1..=MAX_SUBDIVISIONS are clamped to it, with a warning when the settings change.
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub struct HexMapSettings {
+    //see HexGrid::triangulate_grid, for chunks closer than the first of `lods`
+    pub subdivisions: u32,
+    //coarser levels of detail, by increasing distance
+    pub lods: Vec<HexMapLod>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HexMapLod {
+    pub subdivisions: u32,
+    //used for chunks whose center is at least this far from the active camera. with an orthographic camera
+    //it is compared with the height of the view instead, which grows with OrthographicProjection::scale.
+    pub distance: f32,
+}
+
+impl Default for HexMapSettings {
+    fn default() -> HexMapSettings {
+        HexMapSettings{
+            subdivisions: DEFAULT_SUBDIVISIONS,
+            lods: vec![
+                HexMapLod{subdivisions: 2, distance: 400.0},
+                HexMapLod{subdivisions: 1, distance: 800.0},
+            ],
+        }
+    }
+}
+
+impl HexMapSettings {
+    pub fn subdivisions_at(&self, distance: f32) -> u32 {
+        self.lods.iter()
+            .rev()
+            .find(|lod| distance >= lod.distance)
+            .map_or(self.subdivisions, |lod| lod.subdivisions)
+            .clamp(1, MAX_SUBDIVISIONS)
+    }
+
+    //whether every level of detail has subdivisions HexGrid can triangulate
+    pub fn is_valid(&self) -> bool {
+        std::iter::once(self.subdivisions)
+            .chain(self.lods.iter().map(|lod| lod.subdivisions))
+            .all(|subdivisions| (1..=MAX_SUBDIVISIONS).contains(&subdivisions))
+    }
+}
+
+pub struct HexMapPlugin;
+
+impl Plugin for HexMapPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<HexGrid>()
+            .init_asset_loader::<HexMapLoader>()
+            .init_resource::<HexMapSettings>()
+            .add_systems(PostUpdate, mesh_hex_maps);
+    }
+}
+
+//gives new chunks the material of their HexMap, add it to PostUpdate after mesh_hex_maps
+pub fn inherit_hex_map_material<M: Material>(
+    mut commands: Commands,
+    chunks: Query<(Entity, &Parent), Added<HexMapChunk>>,
+    maps: Query<&MeshMaterial3d<M>, With<HexMap>>,
+) {
+    for (entity, parent) in &chunks {
+        if let Ok(material) = maps.get(parent.get()) {
+            commands.entity(entity).insert(material.clone());
+        }
+    }
+}
+
+//the subdivisions of each chunk of a grid, the finest that any of the maps drawing it at `transforms` needs
+fn chunk_subdivisions(
+    grid: &HexGrid,
+    transforms: &[&GlobalTransform],
+    camera: Option<(&GlobalTransform, &Projection)>,
+    settings: &HexMapSettings,
+) -> HashMap<UVec2, u32> {
+    let distance = |position: Vec3| match camera {
+        Some((_, Projection::Orthographic(ortho))) => ortho.area.height(),
+        Some((camera, _)) => camera.translation().distance(position),
+        None => 0.0,
+    };
+    let count = grid.chunk_count(CHUNK_SIZE);
+    (0..count.x)
+        .flat_map(|x| (0..count.y).map(move |z| UVec2::new(x, z)))
+        .map(|chunk| {
+            let center = grid.chunk_center(chunk, CHUNK_SIZE);
+            let distance = transforms.iter()
+                .map(|transform| distance(transform.transform_point(center)))
+                .fold(f32::INFINITY, f32::min);
+            (chunk, settings.subdivisions_at(distance))
+        })
+        .collect()
+}
+
+//the chunk and the ones around it, which have connection strips into it
+fn neighbor_chunks(grid: &HexGrid, chunk: UVec2) -> impl Iterator<Item = UVec2> {
+    let count = grid.chunk_count(CHUNK_SIZE).as_ivec2();
+    let wraps_x = grid.layout().wraps_x();
+    (-1..=1)
+        .flat_map(move |dx| (-1..=1).map(move |dz| chunk.as_ivec2() + IVec2::new(dx, dz)))
+        .map(move |c| if wraps_x { IVec2::new(c.x.rem_euclid(count.x), c.y) } else { c })
+        .filter(move |c| c.cmpge(IVec2::ZERO).all() && c.cmplt(count).all())
+        .map(|c| c.as_uvec2())
+}
+
+//a copy of a grid to mesh from, and the HexGrid::generation it was made at
+type Snapshot = (u64, Arc<HexGrid>);
+
+//a chunk mesh being made from a snapshot, dropping it cancels it
+pub struct ChunkTask {
+    generation: u64,
+    task: Task<Mesh>,
+}
+
+//meshes being made for some chunks of a grid
+type ChunkTasks = HashMap<UVec2, ChunkTask>;
+
+//the snapshot of a grid, copied again if the grid was edited or replaced since it was made
+fn snapshot(
+    snapshots: &mut HashMap<AssetId<HexGrid>, Snapshot>,
+    id: AssetId<HexGrid>,
+    grid: &HexGrid,
+    replaced: bool
+) -> Snapshot {
+    match snapshots.get(&id) {
+        Some(snapshot) if !replaced && snapshot.0 == grid.generation() => snapshot.clone(),
+        _ => {
+            let snapshot = (grid.generation(), Arc::new(grid.clone()));
+            snapshots.insert(id, snapshot.clone());
+            snapshot
+        }
+    }
+}
+
+//starts meshing chunks from a snapshot, replacing and so cancelling the meshes they had in the making
+fn spawn_chunk_tasks(
+    tasks: &mut ChunkTasks,
+    chunks: impl IntoIterator<Item = UVec2>,
+    (generation, grid): &Snapshot,
+    registry: &TerrainRegistry,
+    subdivisions: &HashMap<UVec2, u32>,
+    default_subdivisions: u32
+) {
+    let registry = Arc::new(registry.clone());
+    let subdivisions = Arc::new(subdivisions.clone());
+    let pool = AsyncComputeTaskPool::get();
+    for chunk in chunks {
+        let (grid, registry, subdivisions) = (grid.clone(), registry.clone(), subdivisions.clone());
+        let task = pool.spawn(async move {
+            grid.triangulate_chunk_lod(chunk, CHUNK_SIZE, &registry, |c| {
+                subdivisions.get(&c).copied().unwrap_or(default_subdivisions)
+            })
+        });
+        tasks.insert(chunk, ChunkTask{generation: *generation, task});
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn mesh_hex_maps(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<HexGrid>>,
+    //grids that changed while there was no registry to mesh them with, and whether every chunk has to be meshed
+    mut pending: Local<HashMap<AssetId<HexGrid>, bool>>,
+    //HexGrid::generation of each grid when it was last meshed
+    mut meshed: Local<HashMap<AssetId<HexGrid>, u64>>,
+    //the subdivisions each chunk of a grid was last meshed with
+    mut meshed_subdivisions: Local<HashMap<AssetId<HexGrid>, HashMap<UVec2, u32>>>,
+    //the copy of each grid that chunks are meshed from
+    mut snapshots: Local<HashMap<AssetId<HexGrid>, Snapshot>>,
+    //chunk meshes of each grid still being made, each swapped in when it is done
+    mut meshing: Local<HashMap<AssetId<HexGrid>, ChunkTasks>>,
+    maps: Query<(Entity, Ref<HexMap>, &GlobalTransform, Option<&Children>)>,
+    chunks: Query<&HexMapChunk>,
+    cameras: Query<(&Camera, &GlobalTransform, &Projection)>,
+    grids: Res<Assets<HexGrid>>,
+    registry: Option<Res<TerrainRegistry>>,
+    settings: Res<HexMapSettings>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    for event in events.read() {
+        match event {
+            AssetEvent::LoadedWithDependencies{id} => {
+                pending.insert(*id, true);
+            }
+            AssetEvent::Modified{id} => {
+                pending.entry(*id).or_insert(false);
+            }
+            AssetEvent::Removed{id} => {
+                meshed.remove(id);
+                meshed_subdivisions.remove(id);
+                snapshots.remove(id);
+                meshing.remove(id);
+            }
+            _ => {}
+        }
+    }
+    if settings.is_changed() && !settings.is_valid() {
+        warn!("HexMapSettings subdivisions have to be in 1..={MAX_SUBDIVISIONS}, clamping {settings:?}");
+    }
+    for (_, map, _, _) in &maps {
+        if map.is_added() || settings.is_changed() {
+            pending.insert(map.0.id(), true);
+        }
+    }
+    let Some(registry) = registry else {
+        return;
+    };
+    let default_subdivisions = settings.subdivisions.clamp(1, MAX_SUBDIVISIONS);
+    let camera = cameras.iter()
+        .find(|(camera, _, _)| camera.is_active)
+        .map(|(_, transform, projection)| (transform, projection));
+    //grids that are waiting to be meshed, and the ones already meshed that the camera may have moved around
+    let ids = pending.keys()
+        .chain(meshed.keys())
+        .copied()
+        .collect::<HashSet<AssetId<HexGrid>>>();
+    for id in ids {
+        let full = pending.remove(&id);
+        //not loaded yet, LoadedWithDependencies will bring it back
+        let Some(grid) = grids.get(id) else {
+            continue;
+        };
+        let transforms = maps.iter()
+            .filter(|(_, map, _, _)| map.0.id() == id)
+            .map(|(_, _, transform, _)| transform)
+            .collect::<Vec<&GlobalTransform>>();
+        let subdivisions = chunk_subdivisions(grid, &transforms, camera, &settings);
+
+        let count = grid.chunk_count(CHUNK_SIZE);
+        let mut dirty = HashSet::new();
+        let mut remesh_all = false;
+        if let Some(full) = full {
+            //a grid that was replaced or edited without the setters keeps its generation
+            let last = meshed.insert(id, grid.generation());
+            remesh_all = full || last.is_none_or(|last| grid.generation() <= last);
+            match last {
+                Some(last) if !remesh_all => dirty.extend(grid.dirty_chunks_since(last, CHUNK_SIZE)),
+                _ => dirty.extend((0..count.x).flat_map(|x| (0..count.y).map(move |z| UVec2::new(x, z)))),
+            }
+        }
+        let tasks = meshing.entry(id).or_default();
+        if remesh_all {
+            //meshes of the grid before it was replaced, some may be for chunks it doesn't have any more
+            tasks.clear();
+        }
+        //chunks that were started again from an up to date copy, when their meshes turned out to be old
+        dirty.retain(|chunk| tasks.get(chunk).is_none_or(|task| task.generation != grid.generation()));
+        let last_subdivisions = meshed_subdivisions.insert(id, subdivisions.clone()).unwrap_or_default();
+        for (&chunk, level) in &subdivisions {
+            if last_subdivisions.get(&chunk) != Some(level) {
+                dirty.extend(neighbor_chunks(grid, chunk));
+            }
+        }
+        if dirty.is_empty() {
+            continue;
+        }
+        let snapshot = snapshot(&mut snapshots, id, grid, remesh_all);
+        spawn_chunk_tasks(tasks, dirty, &snapshot, &registry, &subdivisions, default_subdivisions);
+    }
+
+    let mut restart = HashMap::<AssetId<HexGrid>, Vec<UVec2>>::new();
+    for (&id, tasks) in meshing.iter_mut() {
+        let Some(grid) = grids.get(id) else {
+            continue;
+        };
+        let done = tasks.iter()
+            .filter(|(_, task)| task.task.is_finished())
+            .map(|(&chunk, _)| chunk)
+            .collect::<Vec<UVec2>>();
+        let mut chunk_meshes = HashMap::new();
+        for chunk in done {
+            let ChunkTask{generation, task} = tasks.remove(&chunk).unwrap();
+            //edited after the snapshot was taken, which the grid's Modified event only tells next frame
+            if grid.chunk_dirty_since(chunk, generation, CHUNK_SIZE) {
+                restart.entry(id).or_default().push(chunk);
+            } else {
+                chunk_meshes.insert(chunk, meshes.add(block_on(task)));
+            }
+        }
+        if chunk_meshes.is_empty() {
+            continue;
+        }
+        let count = grid.chunk_count(CHUNK_SIZE);
+
+        for (entity, map, _, children) in &maps {
+            if map.0.id() != id {
+                continue;
+            }
+            let mut existing = HashMap::new();
+            for &child in children.into_iter().flatten() {
+                if let Ok(&HexMapChunk(chunk)) = chunks.get(child) {
+                    if chunk.cmplt(count).all() {
+                        existing.insert(chunk, child);
+                    } else {
+                        commands.entity(child).despawn_recursive();
+                    }
+                }
+            }
+            for (&chunk, mesh) in &chunk_meshes {
+                match existing.get(&chunk) {
+                    Some(&child) => {
+                        commands.entity(child).insert(Mesh3d(mesh.clone()));
+                    }
+                    None => {
+                        commands.entity(entity).with_child((HexMapChunk(chunk), Mesh3d(mesh.clone())));
+                    }
+                }
+            }
+        }
+    }
+    for (id, chunks) in restart {
+        let grid = grids.get(id).unwrap();
+        let snapshot = snapshot(&mut snapshots, id, grid, false);
+        let subdivisions = meshed_subdivisions.get(&id).cloned().unwrap_or_default();
+        let tasks = meshing.get_mut(&id).unwrap();
+        spawn_chunk_tasks(tasks, chunks, &snapshot, &registry, &subdivisions, default_subdivisions);
+    }
+    meshing.retain(|_, tasks| !tasks.is_empty());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ATTRIBUTE_TEXTURE_INDEX;
+    use crate::hexgrid::{HexLayout, OffsetCoordinate};
+    use crate::terrain::TerrainType;
+    use super::*;
+
+    fn registry() -> TerrainRegistry {
+        TerrainRegistry {
+            texture: String::new(),
+            layers: 2,
+            types: (0..2)
+                .map(|layer| TerrainType {
+                    name: layer.to_string(),
+                    layer,
+                    movement_cost: 1.0,
+                    color: [1.0; 4],
+                    tags: vec![]
+                })
+                .collect(),
+            texture_handle: Handle::default()
+        }
+    }
+
+    //meshes of a chunk before and after a terrain edit differ in their texture indices
+    fn texture_indices(mesh: &Mesh) -> Vec<u8> {
+        mesh.attribute(ATTRIBUTE_TEXTURE_INDEX).unwrap().get_bytes().to_vec()
+    }
+
+    //the mesh made before an edit must not be swapped in after it, even when it finishes later
+    #[test]
+    fn edits_while_meshing_never_show_stale_meshes() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default(), HexMapPlugin))
+            .init_asset::<Mesh>()
+            .insert_resource(registry());
+        let mut grid = HexGrid::with_layout(3*CHUNK_SIZE, 3*CHUNK_SIZE, HexLayout::default());
+        grid.randomize_terrain(2, &mut HexGrid::rng(1));
+        let handle = app.world_mut().resource_mut::<Assets<HexGrid>>().add(grid);
+        app.world_mut().spawn(HexMap(handle.clone()));
+        //starts meshing every chunk
+        app.update();
+
+        let (chunk, offset) = (UVec2::ONE, OffsetCoordinate::new(CHUNK_SIZE + 3, CHUNK_SIZE + 5));
+        let chunk_mesh = |world: &mut World| world.query::<(&HexMapChunk, &Mesh3d)>()
+            .iter(world)
+            .find(|(c, _)| c.0 == chunk)
+            .map(|(_, mesh)| mesh.0.clone());
+        //the chunk keeps a mesh that was done before the edit until the new one is
+        let before = chunk_mesh(app.world_mut());
+        let mut grids = app.world_mut().resource_mut::<Assets<HexGrid>>();
+        let grid = grids.get_mut(&handle).unwrap();
+        let terrain = grid.terrain[offset];
+        grid.set_terrain(offset, 1 - terrain);
+        let expected = texture_indices(&grid.triangulate_chunk(chunk, CHUNK_SIZE, &registry(), DEFAULT_SUBDIVISIONS));
+
+        let mut shown = false;
+        for _ in 0..1000 {
+            app.update();
+            let world = app.world_mut();
+            if let Some(mesh) = chunk_mesh(world).filter(|mesh| Some(mesh) != before.as_ref()) {
+                let mesh = world.resource::<Assets<Mesh>>().get(&mesh).unwrap();
+                assert!(texture_indices(mesh) == expected, "chunk {chunk} shows the mesh from before the edit");
+                shown = true;
+            }
+            //every chunk has its mesh, so nothing else can be swapped in
+            if shown && world.query::<&Mesh3d>().iter(world).count() == 9 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(shown);
+    }
+
+    #[test]
+    fn subdivisions_are_clamped() {
+        let settings = HexMapSettings{
+            subdivisions: 0,
+            lods: vec![
+                HexMapLod{subdivisions: MAX_SUBDIVISIONS + 5, distance: 100.0},
+                HexMapLod{subdivisions: 2, distance: 200.0},
+            ],
+        };
+        assert!(!settings.is_valid());
+        assert_eq!(settings.subdivisions_at(0.0), 1);
+        assert_eq!(settings.subdivisions_at(150.0), MAX_SUBDIVISIONS);
+        assert_eq!(settings.subdivisions_at(250.0), 2);
+        assert!(HexMapSettings::default().is_valid());
+    }
+}
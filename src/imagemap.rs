@@ -0,0 +1,374 @@
+use std::io::Cursor;
+use std::ops::RangeInclusive;
+use bevy::color::{ColorToComponents, ColorToPacked, LinearRgba, Srgba};
+use image::DynamicImage;
+use thiserror::Error;
+use crate::hexgrid::{HexGrid, HexLayout};
+use crate::terrain::TerrainRegistry;
+
+#[derive(Debug, Error)]
+pub enum ImageMapError {
+    #[error("could not decode PNG: {0}")]
+    Decode(#[from] png::DecodingError),
+    #[error("could not encode PNG: {0}")]
+    Encode(#[from] png::EncodingError),
+    #[error("terrain image must be indexed, found {0:?}")]
+    NotIndexed(png::ColorType),
+    #[error("image is empty")]
+    Empty,
+    #[error("terrain {0} doesn't fit in an 8 bit palette")]
+    TerrainOutOfRange(u32),
+    #[error("could not decode heightmap: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("heightmap has no height range, pass one")]
+    MissingHeightRange,
+}
+
+//tEXt chunk heightmap_png stores its height range in, as "start end"
+const HEIGHT_RANGE_KEYWORD: &str = "hexmap height range";
+
+//pixel under sample i of `count` samples spread evenly over `size` pixels.
+//when count == size every sample gets its own pixel.
+fn pixel(i: usize, count: usize, size: u32) -> u32 {
+    ((2*i as u64 + 1)*size as u64/(2*count as u64)) as u32
+}
+
+//palette indices of an indexed PNG, row by row from the top, and its width and height
+fn decode_indexed(bytes: &[u8]) -> Result<(Vec<u8>, u32, u32), ImageMapError> {
+    let mut decoder = png::Decoder::new(Cursor::new(bytes));
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size().ok_or(ImageMapError::Empty)?];
+    let info = reader.next_frame(&mut buf)?;
+    if info.color_type != png::ColorType::Indexed {
+        return Err(ImageMapError::NotIndexed(info.color_type));
+    }
+    if info.width == 0 || info.height == 0 {
+        return Err(ImageMapError::Empty);
+    }
+    let bits = info.bit_depth as usize;
+    let per_byte = 8/bits;
+    let mask = ((1u16 << bits) - 1) as u8;
+    let indices = buf.chunks(info.line_size)
+        .take(info.height as usize)
+        .flat_map(|line| (0..info.width as usize).map(move |x| {
+            let shift = 8 - bits*(x%per_byte + 1);
+            (line[x/per_byte] >> shift) & mask
+        }))
+        .collect();
+    Ok((indices, info.width, info.height))
+}
+
+//the height range heightmap_png stored in a PNG, if it has one
+pub fn heightmap_png_range(png: &[u8]) -> Result<Option<RangeInclusive<i32>>, ImageMapError> {
+    let reader = png::Decoder::new(Cursor::new(png)).read_info()?;
+    let range = reader.info().uncompressed_latin1_text.iter()
+        .find(|chunk| chunk.keyword == HEIGHT_RANGE_KEYWORD)
+        .and_then(|chunk| {
+            let (start, end) = chunk.text.split_once(' ')?;
+            Some(start.parse().ok()?..=end.parse().ok()?)
+        });
+    Ok(range)
+}
+
+fn encode(
+    width: u32,
+    height: u32,
+    color: png::ColorType,
+    depth: png::BitDepth,
+    palette: Option<Vec<u8>>,
+    text: &[(&str, String)],
+    data: &[u8]
+) -> Result<Vec<u8>, ImageMapError> {
+    let mut bytes = vec![];
+    let mut encoder = png::Encoder::new(&mut bytes, width, height);
+    encoder.set_color(color);
+    encoder.set_depth(depth);
+    if let Some(palette) = palette {
+        encoder.set_palette(palette);
+    }
+    for (keyword, text) in text {
+        encoder.add_text_chunk(keyword.to_string(), text.clone())?;
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(data)?;
+    writer.finish()?;
+    Ok(bytes)
+}
+
+impl HexGrid {
+    //a default layout grid with heights sampled from a heightmap, see apply_heightmap.
+    //the image doesn't know its heights, to read back a heightmap_png pass the range it was made with
+    //or use from_heightmap_png.
+    pub fn from_heightmap(image: &DynamicImage, cells_x: usize, cells_z: usize, height_range: RangeInclusive<i32>) -> HexGrid {
+        let mut grid = HexGrid::with_layout(cells_x, cells_z, HexLayout::default());
+        grid.apply_heightmap(image, height_range);
+        grid
+    }
+
+    //like from_heightmap, for a PNG. without a height_range the one heightmap_png stored in it is used.
+    pub fn from_heightmap_png(
+        png: &[u8],
+        cells_x: usize,
+        cells_z: usize,
+        height_range: Option<RangeInclusive<i32>>
+    ) -> Result<HexGrid, ImageMapError> {
+        let height_range = match height_range {
+            Some(height_range) => height_range,
+            None => heightmap_png_range(png)?.ok_or(ImageMapError::MissingHeightRange)?,
+        };
+        let image = image::load_from_memory_with_format(png, image::ImageFormat::Png)?;
+        Ok(HexGrid::from_heightmap(&image, cells_x, cells_z, height_range))
+    }
+
+    //the image is stretched over the heights lattice with north at the top.
+    //black is the start of height_range and white the end, an empty image changes nothing.
+    pub fn apply_heightmap(&mut self, image: &DynamicImage, height_range: RangeInclusive<i32>) {
+        let image = image.to_luma16();
+        if image.width() == 0 || image.height() == 0 {
+            return;
+        }
+        let (start, end) = (*height_range.start() as i64, *height_range.end() as i64);
+        let width = self.heights.len();
+        for (x, column) in self.heights.iter_mut().enumerate() {
+            let depth = column.len();
+            for (z, height) in column.iter_mut().enumerate() {
+                let px = pixel(x, width, image.width());
+                let py = pixel(depth - 1 - z, depth, image.height());
+                let value = image.get_pixel(px, py).0[0] as f64/u16::MAX as f64;
+                *height = (start + (value*(end - start) as f64).round() as i64) as i32;
+            }
+        }
+    }
+
+    //the palette index of each pixel is a terrain. the image is stretched over the cells
+    //by offset coordinate with north at the top, so an image the size of `cells` has a pixel per cell.
+    pub fn apply_terrain_png(&mut self, png: &[u8]) -> Result<(), ImageMapError> {
+        let (indices, width, height) = decode_indexed(png)?;
+        let cells_x = self.cells.len();
+        let cells_z = self.cells.iter().map(Vec::len).max().unwrap_or(0);
+        for (offset, terrain) in self.terrain.iter_mut() {
+            let px = pixel(offset.x, cells_x, width);
+            let py = pixel(cells_z - 1 - offset.z, cells_z, height);
+            *terrain = indices[(py*width + px) as usize] as u32;
+        }
+        Ok(())
+    }
+
+    //lowest and highest height in the lattice
+    pub fn height_range(&self) -> RangeInclusive<i32> {
+        let heights = self.heights.iter().flatten();
+        let min = heights.clone().copied().min().unwrap_or(0);
+        let max = heights.copied().max().unwrap_or(0);
+        min..=max
+    }
+
+    //16 bit grayscale PNG with a pixel per lattice corner, the inverse of apply_heightmap.
+    //heights outside height_range are clamped to it. the range is kept in a tEXt chunk for
+    //from_heightmap_png, other readers have to be given it. heights only come back exactly
+    //when the range spans at most u16::MAX.
+    pub fn heightmap_png(&self, height_range: RangeInclusive<i32>) -> Result<Vec<u8>, ImageMapError> {
+        let width = self.heights.len();
+        let depth = self.heights.first().map_or(0, Vec::len);
+        if width == 0 || depth == 0 {
+            return Err(ImageMapError::Empty);
+        }
+        let (start, end) = (*height_range.start() as i64, *height_range.end() as i64);
+        let span = (end - start).max(1);
+        let mut data = Vec::with_capacity(width*depth*2);
+        for row in 0..depth {
+            let z = depth - 1 - row;
+            for column in &self.heights {
+                let height = (column[z] as i64).clamp(start, end.max(start)) - start;
+                let value = ((height*u16::MAX as i64 + span/2)/span) as u16;
+                data.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+        let range = format!("{start} {end}");
+        encode(
+            width as u32,
+            depth as u32,
+            png::ColorType::Grayscale,
+            png::BitDepth::Sixteen,
+            None,
+            &[(HEIGHT_RANGE_KEYWORD, range)],
+            &data
+        )
+    }
+
+    //indexed PNG with a pixel per offset coordinate, the inverse of apply_terrain_png.
+    //the palette has the registry's terrain colors, terrain it doesn't know is gray with the index as
+    //its brightness. pixels with no cell are 0.
+    pub fn terrain_png(&self, registry: Option<&TerrainRegistry>) -> Result<Vec<u8>, ImageMapError> {
+        let cells_x = self.cells.len();
+        let cells_z = self.cells.iter().map(Vec::len).max().unwrap_or(0);
+        if cells_x == 0 || cells_z == 0 {
+            return Err(ImageMapError::Empty);
+        }
+        let mut data = vec![0; cells_x*cells_z];
+        for (offset, &terrain) in self.terrain.iter() {
+            let index = u8::try_from(terrain).map_err(|_| ImageMapError::TerrainOutOfRange(terrain))?;
+            data[(cells_z - 1 - offset.z)*cells_x + offset.x] = index;
+        }
+        let palette = (0..=u8::MAX as u32)
+            .flat_map(|terrain| match registry.and_then(|registry| registry.get(terrain)) {
+                Some(t) => Srgba::from(LinearRgba::from_f32_array(t.color)).to_u8_array_no_alpha(),
+                None => [terrain as u8; 3]
+            })
+            .collect();
+        encode(cells_x as u32, cells_z as u32, png::ColorType::Indexed, png::BitDepth::Eight, Some(palette), &[], &data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use crate::hexgrid::OffsetCoordinate;
+    use super::*;
+
+    fn random_heights(grid: &mut HexGrid, range: RangeInclusive<i32>) {
+        let mut rng = HexGrid::rng(11);
+        for column in &mut grid.heights {
+            for height in column {
+                *height = rng.gen_range(range.clone());
+            }
+        }
+    }
+
+    //a PNG with the given palette indices, row by row from the top, packed at `depth` bits per pixel
+    fn indexed_png(width: u32, indices: &[u8], depth: png::BitDepth) -> Vec<u8> {
+        let bits = depth as usize;
+        let row_bytes = (width as usize*bits).div_ceil(8);
+        let mut data = vec![];
+        for row in indices.chunks(width as usize) {
+            let mut packed = vec![0; row_bytes];
+            for (x, &index) in row.iter().enumerate() {
+                packed[x*bits/8] |= index << (8 - bits*(x%(8/bits) + 1));
+            }
+            data.extend(packed);
+        }
+        let height = (indices.len()/width as usize) as u32;
+        let palette = (0..1u32 << bits).flat_map(|i| [i as u8; 3]).collect();
+        encode(width, height, png::ColorType::Indexed, depth, Some(palette), &[], &data).unwrap()
+    }
+
+    #[test]
+    fn terrain_pngs_are_applied_by_offset() {
+        let indices = (0..12).collect::<Vec<u8>>();
+        let png = indexed_png(4, &indices, png::BitDepth::Eight);
+        let mut grid = HexGrid::with_layout(4, 3, HexLayout::default());
+        grid.apply_terrain_png(&png).unwrap();
+        for (offset, &terrain) in grid.terrain.iter() {
+            assert_eq!(terrain, indices[(2 - offset.z)*4 + offset.x] as u32);
+        }
+
+        let mask = vec![vec![true; 3], vec![true, false, true], vec![true; 3], vec![false, true, true]];
+        let mut grid = HexGrid::from_mask(&mask, HexLayout::default());
+        grid.apply_terrain_png(&png).unwrap();
+        assert_eq!(grid.terrain.iter().count(), 10);
+        for (offset, &terrain) in grid.terrain.iter() {
+            assert_eq!(terrain, indices[(2 - offset.z)*4 + offset.x] as u32);
+        }
+
+        //a 2x2 image stretched over the 4x3 grid
+        let png = indexed_png(2, &[1, 2, 3, 4], png::BitDepth::Eight);
+        let mut grid = HexGrid::with_layout(4, 3, HexLayout::default());
+        grid.apply_terrain_png(&png).unwrap();
+        assert_eq!(grid.terrain[OffsetCoordinate::new(0, 2)], 1);
+        assert_eq!(grid.terrain[OffsetCoordinate::new(3, 2)], 2);
+        assert_eq!(grid.terrain[OffsetCoordinate::new(0, 0)], 3);
+        assert_eq!(grid.terrain[OffsetCoordinate::new(3, 0)], 4);
+    }
+
+    #[test]
+    fn terrain_pngs_with_small_palettes() {
+        for depth in [png::BitDepth::One, png::BitDepth::Two, png::BitDepth::Four] {
+            let count = 1u32 << depth as u32;
+            //rows of 7 don't fill their last byte at any of these depths
+            let indices = (0..21).map(|i| (i*5%count) as u8).collect::<Vec<u8>>();
+            let png = indexed_png(7, &indices, depth);
+            let mut grid = HexGrid::with_layout(7, 3, HexLayout::default());
+            grid.apply_terrain_png(&png).unwrap();
+            for (offset, &terrain) in grid.terrain.iter() {
+                assert_eq!(terrain, indices[(2 - offset.z)*7 + offset.x] as u32, "{depth:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn terrain_pngs_have_to_be_indexed() {
+        let mut grid = HexGrid::with_layout(4, 3, HexLayout::default());
+        let png = grid.heightmap_png(0..=10).unwrap();
+        assert!(matches!(
+            grid.apply_terrain_png(&png),
+            Err(ImageMapError::NotIndexed(png::ColorType::Grayscale))
+        ));
+    }
+
+    #[test]
+    fn heightmaps_span_every_i32() {
+        let mut grid = HexGrid::with_layout(3, 3, HexLayout::default());
+        for (luma, height) in [(0, i32::MIN), (u16::MAX, i32::MAX)] {
+            let image = DynamicImage::ImageLuma16(image::ImageBuffer::from_pixel(2, 2, image::Luma([luma])));
+            grid.apply_heightmap(&image, i32::MIN..=i32::MAX);
+            assert!(grid.heights.iter().flatten().all(|&h| h == height));
+        }
+    }
+
+    #[test]
+    fn heightmaps_round_trip() {
+        let mut grid = HexGrid::with_layout(13, 9, HexLayout::default());
+        random_heights(&mut grid, -40..=25);
+        let png = grid.heightmap_png(grid.height_range()).unwrap();
+        assert_eq!(heightmap_png_range(&png).unwrap(), Some(grid.height_range()));
+
+        let read = HexGrid::from_heightmap_png(&png, 13, 9, None).unwrap();
+        assert_eq!(read.heights, grid.heights);
+        let image = image::load_from_memory(&png).unwrap();
+        let read = HexGrid::from_heightmap(&image, 13, 9, grid.height_range());
+        assert_eq!(read.heights, grid.heights);
+    }
+
+    #[test]
+    fn terrain_pngs_round_trip() {
+        let mask = vec![vec![true; 6], vec![true, false, false, true, true, true], vec![true; 6], vec![false; 6]];
+        let grids = [HexGrid::with_layout(9, 6, HexLayout::default()), HexGrid::from_mask(&mask, HexLayout::default())];
+        for mut grid in grids {
+            grid.randomize_terrain(256, &mut HexGrid::rng(4));
+            let png = grid.terrain_png(None).unwrap();
+            let mut read = grid.clone();
+            read.randomize_terrain(256, &mut HexGrid::rng(5));
+            read.apply_terrain_png(&png).unwrap();
+            assert_eq!(read.terrain, grid.terrain);
+
+            //pixels without a cell are 0
+            let (indices, width, height) = decode_indexed(&png).unwrap();
+            assert_eq!((width as usize, height as usize), (grid.cells.len(), grid.cells[0].len()));
+            for x in 0..grid.cells.len() {
+                for z in 0..grid.cells[x].len() {
+                    if !grid.contains(OffsetCoordinate::new(x, z)) {
+                        assert_eq!(indices[(height as usize - 1 - z)*width as usize + x], 0);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn terrain_pngs_need_8_bit_terrain() {
+        let mut grid = HexGrid::with_layout(3, 3, HexLayout::default());
+        grid.set_terrain(OffsetCoordinate::new(1, 1), 256);
+        assert!(matches!(grid.terrain_png(None), Err(ImageMapError::TerrainOutOfRange(256))));
+    }
+
+    #[test]
+    fn heightmaps_without_a_range() {
+        let grid = HexGrid::with_layout(4, 4, HexLayout::default());
+        let png = grid.terrain_png(None).unwrap();
+        assert_eq!(heightmap_png_range(&png).unwrap(), None);
+        assert!(matches!(
+            HexGrid::from_heightmap_png(&png, 4, 4, None),
+            Err(ImageMapError::MissingHeightRange)
+        ));
+    }
+}
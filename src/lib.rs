@@ -0,0 +1,12 @@
+pub mod export;
+pub mod hexgrid;
+pub mod hexmap;
+pub mod imagemap;
+pub mod mapfile;
+pub mod terrain;
+
+use bevy::render::mesh::MeshVertexAttribute;
+use bevy::render::render_resource::VertexFormat;
+
+pub const ATTRIBUTE_TEXTURE_INDEX: MeshVertexAttribute =
+    MeshVertexAttribute::new("TextureIndex", 988540917, VertexFormat::Uint32x3 );
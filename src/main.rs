@@ -2,16 +2,7 @@
 //! assign a custom UV mapping for a custom texture,
 //! and how to change the UV mapping at run-time.
 
-mod hexgrid;
-
-use bevy::{
-    prelude::*,
-    render::{
-        mesh::{Indices, VertexAttributeValues},
-        render_asset::RenderAssetUsages,
-        render_resource::PrimitiveTopology,
-    },
-};
+use bevy::prelude::*;
 
 use bevy_egui::{egui, EguiContexts, EguiPlugin};
 
@@ -21,10 +12,15 @@ use bevy::{
 use bevy::pbr::{ExtendedMaterial, MaterialExtension, MaterialExtensionKey, MaterialExtensionPipeline, OpaqueRendererMethod};
 use bevy::picking::pointer::PointerInteraction;
 use bevy::render::camera::ScalingMode;
-use bevy::render::mesh::{MeshVertexAttribute, MeshVertexBufferLayoutRef};
-use bevy::render::render_resource::{AsBindGroup, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError, VertexFormat};
+use bevy::render::mesh::MeshVertexBufferLayoutRef;
+use bevy::render::render_resource::{AsBindGroup, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError};
 //use bevy::image::{ImageAddressMode, ImageLoaderSettings, ImageSampler, ImageSamplerDescriptor};
-use crate::hexgrid::{HexGrid, OffsetCoordinate};
+use bevy_hex::hexgrid::{HexDirection, HexGrid, OffsetCoordinate, MAX_SUBDIVISIONS};
+use bevy_hex::hexmap::{inherit_hex_map_material, mesh_hex_maps, HexMap, HexMapPlugin, HexMapSettings};
+use bevy_hex::terrain::{TerrainPlugin, TerrainRegistry};
+use bevy_hex::ATTRIBUTE_TEXTURE_INDEX;
+
+type HexTerrainMaterial = ExtendedMaterial<StandardMaterial, HexTerrainExtension>;
 
 // Define a "marker" component to mark the custom mesh. Marker components are often used in Bevy for
 // filtering entities in queries with `With`, they're usually not queried directly since they don't
@@ -36,19 +32,29 @@ fn main() {
             DefaultPlugins,
             MeshPickingPlugin,
             EguiPlugin,
-            MaterialPlugin::<ExtendedMaterial<StandardMaterial, HexTerrainExtension>,>::default()
+            TerrainPlugin,
+            HexMapPlugin,
+            MaterialPlugin::<HexTerrainMaterial>::default()
         ))
         .add_systems(Startup, setup)
-        .add_systems(Update, (create_map, input_handler, ui_system))
+        .add_systems(Update, (create_map, update_map_copies, input_handler, ui_system))
+        .add_systems(PostUpdate, inherit_hex_map_material::<HexTerrainMaterial>.after(mesh_hex_maps))
         .insert_resource(SelectedTile(None))
-        .insert_resource(HexGrid::new(50, 25))
         .run();
 }
 
 #[derive(Resource)]
-struct LoadingTexture {
+struct LoadingTerrain {
     is_loaded: bool,
-    handle: Handle<Image>,
+    handle: Handle<TerrainRegistry>,
+}
+
+#[derive(Resource)]
+struct Map {
+    handle: Handle<HexGrid>,
+    material: Handle<HexTerrainMaterial>,
+    // Where the copies of a wrapping map were spawned.
+    wrap_offset: Option<Vec3>,
 }
 
 fn setup(
@@ -62,9 +68,14 @@ fn setup(
 ) {
     // let test_grid = grid;
     // let hex_mesh_handle: Handle<Mesh> = meshes.add(test_grid.triangulate_grid());
-    commands.insert_resource(LoadingTexture {
+    commands.insert_resource(LoadingTerrain {
         is_loaded: false,
-        handle: asset_server.load("textures/array_texture.png"),
+        handle: asset_server.load("terrain/default.terrain.ron"),
+    });
+    commands.insert_resource(Map {
+        handle: asset_server.load("maps/demo.hexmap"),
+        material: Handle::default(),
+        wrap_offset: None,
     });
 
     //Render the mesh with the custom texture, and add the marker.
@@ -118,33 +129,38 @@ fn setup(
 }
 
 
+#[allow(clippy::too_many_arguments)]
 fn create_map(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
-    mut loading_texture: ResMut<LoadingTexture>,
+    mut loading_terrain: ResMut<LoadingTerrain>,
+    registries: Res<Assets<TerrainRegistry>>,
     mut images: ResMut<Assets<Image>>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, HexTerrainExtension>>>,
-    grid: Res<HexGrid>
+    mut materials: ResMut<Assets<HexTerrainMaterial>>,
+    mut map: ResMut<Map>,
+    grids: Res<Assets<HexGrid>>,
 ) {
-    if loading_texture.is_loaded
-        || !asset_server
-        .load_state(loading_texture.handle.id())
-        .is_loaded()
+    if loading_terrain.is_loaded
+        || !asset_server.is_loaded_with_dependencies(loading_terrain.handle.id())
     {
         return;
     }
-    loading_texture.is_loaded = true;
-    let image = images.get_mut(&loading_texture.handle).unwrap();
+    // The mesh is built by HexMapPlugin, but the copies of a wrapping map need its size.
+    let Some(grid) = grids.get(&map.handle) else {
+        return;
+    };
+    loading_terrain.is_loaded = true;
+    let registry = registries.get(&loading_terrain.handle).unwrap().clone();
+    let image = images.get_mut(&registry.texture_handle).unwrap();
+    if let Err(err) = registry.check_texture(image) {
+        error!("{}: {err}", registry.texture);
+        return;
+    }
 
     // Create a new array texture asset from the loaded texture.
-    let array_layers = 4;
-    image.reinterpret_stacked_2d_as_array(array_layers);
-
-    let test_grid = grid;
-    let hex_mesh_handle: Handle<Mesh> = meshes.add(test_grid.triangulate_grid());
+    image.reinterpret_stacked_2d_as_array(registry.layers);
 
-    let material_handle: Handle<ExtendedMaterial<StandardMaterial, HexTerrainExtension>> = materials.add({
+    map.material = materials.add({
         ExtendedMaterial{
             base: StandardMaterial {
                 base_color: WHITE.into(),
@@ -154,17 +170,55 @@ fn create_map(
                 ..Default::default()
             },
             extension: HexTerrainExtension {
-                array_texture: loading_texture.handle.clone(),
+                array_texture: registry.texture_handle.clone(),
             }
         }
     });
 
-    commands.spawn((
-        Mesh3d(hex_mesh_handle.clone()),
-        MeshMaterial3d(material_handle.clone()),
+    spawn_map(&mut commands, &mut map, grid);
+    commands.insert_resource(registry);
+}
 
-    ))
-    .observe(clicked_map);
+// Reloading the map file can change its size or whether it wraps, which moves the copies.
+fn update_map_copies(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<HexGrid>>,
+    mut map: ResMut<Map>,
+    grids: Res<Assets<HexGrid>>,
+    query: Query<Entity, With<HexMap>>,
+) {
+    let modified = events.read().any(|event| event.is_modified(&map.handle));
+    let Some(grid) = grids.get(&map.handle) else {
+        return;
+    };
+    if !modified || query.is_empty() || grid.wrap_offset() == map.wrap_offset {
+        return;
+    }
+    for entity in &query {
+        commands.entity(entity).despawn_recursive();
+    }
+    spawn_map(&mut commands, &mut map, grid);
+}
+
+// Wrapping maps get a copy on either side, so that the seam is never visible.
+fn spawn_map(
+    commands: &mut Commands,
+    map: &mut Map,
+    grid: &HexGrid,
+) {
+    map.wrap_offset = grid.wrap_offset();
+    let offsets = match map.wrap_offset {
+        Some(offset) => vec![-offset, Vec3::ZERO, offset],
+        None => vec![Vec3::ZERO]
+    };
+    for offset in offsets {
+        commands.spawn((
+            HexMap(map.handle.clone()),
+            MeshMaterial3d(map.material.clone()),
+            Transform::from_translation(offset),
+        ))
+        .observe(clicked_map);
+    }
 }
 
 // System to receive input from the user,
@@ -175,6 +229,8 @@ fn input_handler(
     //mut meshes: ResMut<Assets<Mesh>>,
     mut query: Query<&mut Transform, With<Camera3d>>,
     time: Res<Time>,
+    map: Res<Map>,
+    grids: Res<Assets<HexGrid>>,
 ) {
     if keyboard_input.pressed(KeyCode::KeyW) {
         for mut transform in &mut query {
@@ -201,6 +257,15 @@ fn input_handler(
             transform.look_to(Vec3::NEG_Z, Vec3::Y);
         }
     }
+    if let Some(offset) = grids.get(&map.handle).and_then(HexGrid::wrap_offset) {
+        for mut transform in &mut query {
+            if transform.translation.x < 0.0 {
+                transform.translation += offset;
+            } else if transform.translation.x >= offset.x {
+                transform.translation -= offset;
+            }
+        }
+    }
 }
 
 #[derive(Resource)]
@@ -208,113 +273,112 @@ struct SelectedTile (Option<OffsetCoordinate>);
 fn clicked_map(
     _: Trigger<Pointer<Click>>,
     pointers: Query<&PointerInteraction>,
-    mut selected_tile: ResMut<SelectedTile>
+    mut selected_tile: ResMut<SelectedTile>,
+    map: Res<Map>,
+    grids: Res<Assets<HexGrid>>,
 ) {
+    let Some(grid) = grids.get(&map.handle) else {
+        return;
+    };
     for (point, _) in pointers
         .iter()
         .filter_map(|interaction| interaction.get_nearest_hit())
         .filter_map(|(_entity, hit)| hit.position.zip(hit.normal))
     {
         //println!("{}, {}", point.x, point.z);
-        let hex_idx = OffsetCoordinate::from_position(point);
-        selected_tile.0 = Some(hex_idx);
-        println!("{}, {}", hex_idx.x, hex_idx.z);
+        let hex_idx = grid.cell_at_position(point);
+        selected_tile.0 = hex_idx;
+        if let Some(hex_idx) = hex_idx {
+            println!("{}, {}", hex_idx.x, hex_idx.z);
+        }
     }
 }
 
+// Edits are made on a copy and written back to the asset, which HexMapPlugin then meshes again.
+// Getting the asset mutably every frame would do that every frame.
 fn ui_system(
     mut contexts: EguiContexts,
     selected_tile: Res<SelectedTile>,
-    mut grid: ResMut<HexGrid>,
-    mut commands: Commands,
-    query: Query<Entity, With<Mesh3d>>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, HexTerrainExtension>>>,
-    mut loading_texture: ResMut<LoadingTexture>,
+    map: Res<Map>,
+    mut grids: ResMut<Assets<HexGrid>>,
+    registry: Option<Res<TerrainRegistry>>,
+    mut settings: ResMut<HexMapSettings>,
 ) {
-    //NE: 0
-    // W: 1
-    //SE: 2
-    //SW: 3
-    // E: 4
-    //NW: 5
-    let dir_names = ["N ", "NE", "SE", "S ", "SW", "NW"];
-    let mut changed = false;
+    let Some(grid) = grids.get(&map.handle) else {
+        return;
+    };
+    let mut edit = None;
+    let mut subdivisions = settings.subdivisions;
     egui::Window::new("Test").show(contexts.ctx_mut(), |ui| {
+        ui.add(egui::Slider::new(&mut subdivisions, 1..=MAX_SUBDIVISIONS).text("Subdivisions"));
         match selected_tile.0 {
             None => {ui.label("No selected tile.");}
             Some(idx) => {
                 ui.label(format!("Selected: {}, {}", idx.x, idx.z));
 
-                let tile = &grid.cells[idx.x][idx.z];
-                let height_refs = tile.height_refs.clone();
-                for (i, &(hx, hz)) in height_refs.iter().enumerate() {
-                    ui.label(dir_names[i]);
-                    changed = ui.add(egui::Slider::new(&mut grid.heights[hx][hz], 0..=5)).changed() || changed;
+                let Some(height_refs) = grid.cell(idx).map(|tile| tile.height_refs) else {
+                    return;
+                };
+                let mut heights = height_refs.0.map(|corner| grid.heights[corner.x][corner.z]);
+                let mut terrain = grid.terrain[idx];
+                let mut changed = false;
+                if let Some(registry) = &registry {
+                    egui::ComboBox::from_label("Terrain")
+                        .selected_text(registry.get(terrain).map_or("Unknown", |t| t.name.as_str()))
+                        .show_ui(ui, |ui| {
+                            for (id, terrain_type) in registry.iter() {
+                                ui.selectable_value(&mut terrain, id, &terrain_type.name);
+                            }
+                        });
+                    changed = terrain != grid.terrain[idx];
+                }
+                for (dir, height) in HexDirection::all().into_iter().zip(&mut heights) {
+                    ui.label(dir.corner_name(grid.layout().orientation()));
+                    changed = ui.add(egui::Slider::new(height, 0..=5)).changed() || changed;
                     ui.end_row();
                 }
                 ui.horizontal(|ui| {
                     if ui.button("Raise").clicked() {
-                        for &(hx, hz) in height_refs.iter() {
-                            grid.heights[hx][hz] += 1;
+                        for height in &mut heights {
+                            *height += 1;
                         }
                         changed = true;
                     }
                     if ui.button("Lower").clicked() {
-                        for &(hx, hz) in height_refs.iter() {
-                            grid.heights[hx][hz] -= 1;
+                        for height in &mut heights {
+                            *height -= 1;
                         }
                         changed = true;
                     }
                     if ui.button("Flatten").clicked() {
-                        let mut sum = 0;
-                        for &(hx, hz) in height_refs.iter() {
-                            sum += grid.heights[hx][hz];
-                        }
-                        sum = (sum as f32/6.0).round() as i32;
-                        for &(hx, hz) in height_refs.iter() {
-                            grid.heights[hx][hz] = sum;
-                        }
+                        let sum: i32 = heights.iter().sum();
+                        heights = [(sum as f32/6.0).round() as i32; 6];
                         changed = true;
                     }
                 });
-
+                if changed {
+                    edit = Some((idx, height_refs, heights, terrain));
+                }
             }
         }
     });
+    if subdivisions != settings.subdivisions {
+        settings.subdivisions = subdivisions;
+    }
 
-    if changed {
-        for entity in query.iter() {
-            commands.entity(entity).despawn();
-            let hex_mesh_handle: Handle<Mesh> = meshes.add(grid.triangulate_grid());
-
-            let material_handle: Handle<ExtendedMaterial<StandardMaterial, HexTerrainExtension>> = materials.add({
-                ExtendedMaterial{
-                    base: StandardMaterial {
-                        base_color: WHITE.into(),
-                        opaque_render_method: OpaqueRendererMethod::Auto,
-                        reflectance: 0.1,
-                        perceptual_roughness: 0.9,
-                        ..Default::default()
-                    },
-                    extension: HexTerrainExtension {
-                        array_texture: loading_texture.handle.clone(),
-                    }
-                }
-            });
-
-            commands.spawn((
-                Mesh3d(hex_mesh_handle.clone()),
-                MeshMaterial3d(material_handle.clone()),
-            ))
-                .observe(clicked_map);
+    if let Some((idx, height_refs, heights, terrain)) = edit {
+        let grid = grids.get_mut(&map.handle).unwrap();
+        for (corner, height) in height_refs.0.into_iter().zip(heights) {
+            if grid.height_at_corner(corner) != Some(height) {
+                grid.set_height_at_corner(corner, height);
+            }
+        }
+        if grid.terrain[idx] != terrain {
+            grid.set_terrain(idx, terrain);
         }
     }
 }
 
-const ATTRIBUTE_TEXTURE_INDEX: MeshVertexAttribute =
-    MeshVertexAttribute::new("TextureIndex", 988540917, VertexFormat::Uint32x3 );
-
 #[derive(Asset, AsBindGroup, Reflect, Debug, Clone)]
 struct HexTerrainExtension {
     // We need to ensure that the bindings of the base material and the extension do not conflict,
@@ -0,0 +1,340 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::hexgrid::{
+    HexCoordinate, HexDirection, HexEdge, HexEdgeData, HexGrid, HexLayout, HexOrientation, OffsetLayout
+};
+
+//maps are saved either as RON, starting with `(version: N, map: ...)`, or as MAGIC, N as a little endian
+//u32 and the bincode of the map. from_ron and from_binary read each version with its own struct and hand it
+//to migrate, which is where a new version converts the older ones.
+//version history:
+//1: layout, map shape, heights, terrain and edge data
+pub const MAP_VERSION: u32 = 1;
+const MAGIC: &[u8; 4] = b"HXMP";
+
+#[derive(Debug, Error)]
+pub enum MapFileError {
+    #[error("could not access map file: {0}")]
+    Io(#[from] io::Error),
+    #[error("map file is not valid RON: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+    #[error("could not write map as RON: {0}")]
+    RonWrite(#[from] ron::Error),
+    #[error("binary map file is corrupt: {0}")]
+    Binary(#[from] bincode::Error),
+    #[error("not a map file")]
+    BadMagic,
+    #[error("map file version {0} is not supported, the newest is {MAP_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("map file is inconsistent: {0}")]
+    Invalid(String),
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct LayoutV1 {
+    orientation: HexOrientation,
+    offset_layout: OffsetLayout,
+    wrap_x: bool,
+    outer_radius: f32,
+    solid_factor: f32,
+    elevation_step: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EdgeV1 {
+    //cube coordinate of HexEdge::hex
+    x: i32,
+    z: i32,
+    dir: HexDirection,
+    data: HexEdgeData,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MapV1 {
+    layout: LayoutV1,
+    //[x][z], whether there is a cell
+    mask: Vec<Vec<bool>>,
+    //[x][z], like HexGrid::heights
+    heights: Vec<Vec<i32>>,
+    //one per cell, in HexGrid::iter_cells order
+    terrain: Vec<u32>,
+    edges: Vec<EdgeV1>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Versioned<T> {
+    version: u32,
+    map: T,
+}
+
+#[derive(Deserialize)]
+struct MapHeader {
+    version: u32,
+}
+
+impl LayoutV1 {
+    fn to_layout(self) -> Result<HexLayout, MapFileError> {
+        if !self.outer_radius.is_finite() || self.outer_radius <= 0.0 {
+            return Err(MapFileError::Invalid(format!("outer radius {} is not positive and finite", self.outer_radius)));
+        }
+        if !(self.solid_factor > 0.0 && self.solid_factor <= 1.0) {
+            return Err(MapFileError::Invalid(format!("solid factor {} is not in (0, 1]", self.solid_factor)));
+        }
+        if !self.elevation_step.is_finite() {
+            return Err(MapFileError::Invalid(format!("elevation step {} is not finite", self.elevation_step)));
+        }
+        Ok(HexLayout::new(self.orientation)
+            .with_outer_radius(self.outer_radius)
+            .with_offset_layout(self.offset_layout)
+            .with_wrap_x(self.wrap_x)
+            .with_solid_factor(self.solid_factor)
+            .with_elevation_step(self.elevation_step))
+    }
+}
+
+impl MapV1 {
+    fn from_grid(grid: &HexGrid) -> MapV1 {
+        let layout = grid.layout();
+        let mut edges = grid.edges.iter()
+            .map(|(edge, &data)| EdgeV1 {
+                x: edge.hex().x(),
+                z: edge.hex().z(),
+                dir: edge.direction(),
+                data,
+            })
+            .collect::<Vec<EdgeV1>>();
+        edges.sort_by_key(|e| (e.x, e.z, e.dir));
+        MapV1 {
+            layout: LayoutV1 {
+                orientation: layout.orientation(),
+                offset_layout: layout.offset_layout(),
+                wrap_x: layout.wraps_x(),
+                outer_radius: layout.outer_radius(),
+                solid_factor: layout.solid_factor(),
+                elevation_step: layout.elevation_step(),
+            },
+            mask: grid.cells.iter()
+                .map(|column| column.iter().map(|cell| cell.is_some()).collect())
+                .collect(),
+            heights: grid.heights.clone(),
+            terrain: grid.terrain.iter().map(|(_, &terrain)| terrain).collect(),
+            edges,
+        }
+    }
+
+    fn into_grid(self) -> Result<HexGrid, MapFileError> {
+        let layout = self.layout.to_layout()?;
+        if layout.wraps_x() && !layout.can_wrap_x() {
+            return Err(MapFileError::Invalid(format!(
+                "{:?} hexes in {:?} layout can't wrap",
+                layout.orientation(),
+                layout.offset_layout()
+            )));
+        }
+        if layout.wraps_x() && !layout.offset_layout().is_rows() && !self.mask.len().is_multiple_of(2) {
+            return Err(MapFileError::Invalid("wrapping column layouts need an even number of columns".into()));
+        }
+        let mut grid = HexGrid::from_mask(&self.mask, layout);
+
+        let (width, depth) = (grid.heights.len(), grid.heights.first().map_or(0, Vec::len));
+        if self.heights.len() != width || self.heights.iter().any(|column| column.len() != depth) {
+            return Err(MapFileError::Invalid(format!("height lattice is not {}x{}", width, depth)));
+        }
+        grid.heights = self.heights;
+
+        let cell_count = grid.iter_cells().count();
+        if self.terrain.len() != cell_count {
+            return Err(MapFileError::Invalid(format!(
+                "{} terrain values for {} cells",
+                self.terrain.len(),
+                cell_count
+            )));
+        }
+        for ((_, terrain), value) in grid.terrain.iter_mut().zip(self.terrain) {
+            *terrain = value;
+        }
+
+        for edge in self.edges {
+            let hex_edge = HexEdge::new(HexCoordinate::new(edge.x, edge.z), edge.dir);
+            if !grid.set_edge_data(hex_edge, edge.data) {
+                return Err(MapFileError::Invalid(format!("edge {:?} is outside the map", hex_edge)));
+            }
+        }
+        Ok(grid)
+    }
+}
+
+//brings a map read as `version` up to MAP_VERSION, one version at a time
+fn migrate(version: u32, map: MapV1) -> Result<MapV1, MapFileError> {
+    match version {
+        MAP_VERSION => Ok(map),
+        _ => Err(MapFileError::UnsupportedVersion(version)),
+    }
+}
+
+impl HexGrid {
+    pub fn to_ron(&self) -> Result<String, MapFileError> {
+        let file = Versioned{version: MAP_VERSION, map: MapV1::from_grid(self)};
+        Ok(ron::ser::to_string_pretty(&file, ron::ser::PrettyConfig::default())?)
+    }
+
+    pub fn from_ron(text: &str) -> Result<HexGrid, MapFileError> {
+        let version = ron::de::from_str::<MapHeader>(text)?.version;
+        let map = match version {
+            1 => ron::de::from_str::<Versioned<MapV1>>(text)?.map,
+            _ => return Err(MapFileError::UnsupportedVersion(version)),
+        };
+        migrate(version, map)?.into_grid()
+    }
+
+    pub fn to_binary(&self) -> Result<Vec<u8>, MapFileError> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&MAP_VERSION.to_le_bytes());
+        bincode::serialize_into(&mut bytes, &MapV1::from_grid(self))?;
+        Ok(bytes)
+    }
+
+    pub fn from_binary(bytes: &[u8]) -> Result<HexGrid, MapFileError> {
+        let Some((header, payload)) = bytes.split_first_chunk::<8>() else {
+            return Err(MapFileError::BadMagic);
+        };
+        if &header[..4] != MAGIC {
+            return Err(MapFileError::BadMagic);
+        }
+        let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let map = match version {
+            1 => bincode::deserialize::<MapV1>(payload)?,
+            _ => return Err(MapFileError::UnsupportedVersion(version)),
+        };
+        migrate(version, map)?.into_grid()
+    }
+
+    //either form, told apart by the magic number
+    pub fn from_bytes(bytes: &[u8]) -> Result<HexGrid, MapFileError> {
+        if bytes.starts_with(MAGIC) {
+            return HexGrid::from_binary(bytes);
+        }
+        let text = std::str::from_utf8(bytes).map_err(|_| MapFileError::BadMagic)?;
+        HexGrid::from_ron(text)
+    }
+
+    //RON if the path ends in .ron, binary otherwise
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), MapFileError> {
+        let path = path.as_ref();
+        if path.extension().is_some_and(|ext| ext == "ron") {
+            fs::write(path, self.to_ron()?)?;
+        } else {
+            fs::write(path, self.to_binary()?)?;
+        }
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<HexGrid, MapFileError> {
+        HexGrid::from_bytes(&fs::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use crate::hexgrid::OffsetCoordinate;
+    use super::*;
+
+    fn grid() -> HexGrid {
+        let layout = HexLayout::new(HexOrientation::FlatTop)
+            .with_offset_layout(OffsetLayout::EvenQ)
+            .with_wrap_x(true)
+            .with_outer_radius(4.0)
+            .with_solid_factor(0.6)
+            .with_elevation_step(1.5);
+        let mut mask = vec![vec![true; 5]; 6];
+        mask[2][3] = false;
+        let mut grid = HexGrid::from_mask(&mask, layout);
+        grid.randomize_terrain(5, &mut HexGrid::rng(1));
+        let mut rng = HexGrid::rng(2);
+        for column in &mut grid.heights {
+            for height in column {
+                *height = rng.gen_range(-3..8);
+            }
+        }
+        let edge = grid.edge(OffsetCoordinate::new(0, 1), HexDirection::West);
+        grid.set_edge_data(edge, HexEdgeData{river: true, road: false, wall: true});
+        let edge = grid.edge(OffsetCoordinate::new(4, 4), HexDirection::NorthEast);
+        grid.set_edge_data(edge, HexEdgeData{road: true, ..HexEdgeData::default()});
+        grid
+    }
+
+    fn assert_same(a: &HexGrid, b: &HexGrid) {
+        assert_eq!(a.layout(), b.layout());
+        let mask = |grid: &HexGrid| grid.cells.iter()
+            .map(|column| column.iter().map(Option::is_some).collect())
+            .collect::<Vec<Vec<bool>>>();
+        assert_eq!(mask(a), mask(b));
+        assert_eq!(a.heights, b.heights);
+        assert_eq!(a.terrain, b.terrain);
+        assert_eq!(a.edges, b.edges);
+    }
+
+    #[test]
+    fn round_trips() {
+        let grid = grid();
+        assert_same(&grid, &HexGrid::from_ron(&grid.to_ron().unwrap()).unwrap());
+        assert_same(&grid, &HexGrid::from_binary(&grid.to_binary().unwrap()).unwrap());
+        assert_same(&grid, &HexGrid::from_bytes(grid.to_ron().unwrap().as_bytes()).unwrap());
+        assert_same(&grid, &HexGrid::from_bytes(&grid.to_binary().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn unsupported_versions() {
+        let grid = grid();
+        let ron = grid.to_ron().unwrap().replacen(&format!("version: {}", MAP_VERSION), "version: 99", 1);
+        assert!(matches!(HexGrid::from_ron(&ron), Err(MapFileError::UnsupportedVersion(99))));
+
+        let mut bytes = grid.to_binary().unwrap();
+        bytes[4..8].copy_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(HexGrid::from_binary(&bytes), Err(MapFileError::UnsupportedVersion(0))));
+
+        let map = MapV1::from_grid(&grid);
+        assert!(matches!(migrate(MAP_VERSION + 1, map), Err(MapFileError::UnsupportedVersion(v)) if v == MAP_VERSION + 1));
+        assert!(migrate(MAP_VERSION, MapV1::from_grid(&grid)).is_ok());
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut bytes = grid().to_binary().unwrap();
+        bytes[0] = b'X';
+        assert!(matches!(HexGrid::from_binary(&bytes), Err(MapFileError::BadMagic)));
+        assert!(matches!(HexGrid::from_bytes(&[0xff, 0xfe, 0x00]), Err(MapFileError::BadMagic)));
+    }
+
+    #[test]
+    fn truncated_files() {
+        let grid = grid();
+        let bytes = grid.to_binary().unwrap();
+        assert!(matches!(HexGrid::from_binary(&bytes[..6]), Err(MapFileError::BadMagic)));
+        assert!(matches!(HexGrid::from_binary(&bytes[..bytes.len()/2]), Err(MapFileError::Binary(_))));
+        let ron = grid.to_ron().unwrap();
+        assert!(matches!(HexGrid::from_ron(&ron[..ron.len()/2]), Err(MapFileError::Ron(_))));
+    }
+
+    #[test]
+    fn invalid_layouts() {
+        let ron = grid().to_ron().unwrap();
+        let cases = [
+            ("outer_radius: 4.0", "outer_radius: -1.0"),
+            ("outer_radius: 4.0", "outer_radius: inf"),
+            ("solid_factor: 0.6", "solid_factor: 1.5"),
+            ("elevation_step: 1.5", "elevation_step: inf"),
+            ("elevation_step: 1.5", "elevation_step: NaN"),
+            ("orientation: FlatTop", "orientation: PointyTop"),
+        ];
+        for (field, value) in cases {
+            assert!(ron.contains(field));
+            let ron = ron.replacen(field, value, 1);
+            assert!(matches!(HexGrid::from_ron(&ron), Err(MapFileError::Invalid(_))), "{}", value);
+        }
+    }
+}
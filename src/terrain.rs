@@ -0,0 +1,150 @@
+use bevy::asset::io::Reader;
+use bevy::asset::{AssetLoader, LoadContext};
+use bevy::prelude::*;
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TerrainType {
+    pub name: String,
+    //layer of the registry's array texture that cells of this type are drawn with
+    pub layer: u32,
+    pub movement_cost: f32,
+    //linear rgba, for minimaps and exports
+    pub color: [f32; 4],
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl TerrainType {
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+}
+
+//the terrain types that HexGrid::terrain indexes into, loaded from a .terrain.ron file
+#[derive(Asset, Resource, TypePath, Clone, Debug, Deserialize)]
+pub struct TerrainRegistry {
+    //asset path of an image with `layers` textures stacked vertically
+    pub texture: String,
+    pub layers: u32,
+    pub types: Vec<TerrainType>,
+    #[serde(skip)]
+    #[dependency]
+    pub texture_handle: Handle<Image>,
+}
+
+impl TerrainRegistry {
+    pub fn get(&self, terrain: u32) -> Option<&TerrainType> {
+        self.types.get(terrain as usize)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &TerrainType)> {
+        self.types.iter().enumerate().map(|(i, t)| (i as u32, t))
+    }
+
+    pub fn find(&self, name: &str) -> Option<u32> {
+        self.iter().find(|(_, t)| t.name == name).map(|(i, _)| i)
+    }
+
+    pub fn with_tag<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = u32> + 'a {
+        self.iter().filter(move |(_, t)| t.has_tag(tag)).map(|(i, _)| i)
+    }
+
+    //texture layer to draw a terrain with, unknown terrain is drawn with layer 0
+    pub fn texture_layer(&self, terrain: u32) -> u32 {
+        self.get(terrain).map_or(0, |t| t.layer)
+    }
+
+    pub fn validate(&self) -> Result<(), TerrainRegistryError> {
+        if self.layers == 0 {
+            return Err(TerrainRegistryError::NoLayers);
+        }
+        if self.types.is_empty() {
+            return Err(TerrainRegistryError::NoTypes);
+        }
+        for (i, terrain) in self.types.iter().enumerate() {
+            if terrain.layer >= self.layers {
+                return Err(TerrainRegistryError::LayerOutOfRange{
+                    name: terrain.name.clone(),
+                    layer: terrain.layer,
+                    layers: self.layers
+                });
+            }
+            if self.types[..i].iter().any(|t| t.name == terrain.name) {
+                return Err(TerrainRegistryError::DuplicateName(terrain.name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    //whether `image` can be split into the registry's layers
+    pub fn check_texture(&self, image: &Image) -> Result<(), TerrainRegistryError> {
+        let height = image.height();
+        if self.layers == 0 || !height.is_multiple_of(self.layers) {
+            return Err(TerrainRegistryError::TextureSize{height, layers: self.layers});
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TerrainRegistryError {
+    #[error("could not read terrain registry: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not parse terrain registry: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+    #[error("terrain registry has no texture layers")]
+    NoLayers,
+    #[error("terrain registry has no terrain types")]
+    NoTypes,
+    #[error("terrain type {name:?} uses texture layer {layer}, but the texture only has {layers} layers")]
+    LayerOutOfRange {
+        name: String,
+        layer: u32,
+        layers: u32
+    },
+    #[error("terrain type {0:?} is defined more than once")]
+    DuplicateName(String),
+    #[error("texture is {height} pixels tall, which can't be split into {layers} layers")]
+    TextureSize {
+        height: u32,
+        layers: u32
+    },
+}
+
+#[derive(Default)]
+pub struct TerrainRegistryLoader;
+
+impl AssetLoader for TerrainRegistryLoader {
+    type Asset = TerrainRegistry;
+    type Settings = ();
+    type Error = TerrainRegistryError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<TerrainRegistry, TerrainRegistryError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let mut registry = ron::de::from_bytes::<TerrainRegistry>(&bytes)?;
+        registry.validate()?;
+        registry.texture_handle = load_context.load(registry.texture.clone());
+        Ok(registry)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["terrain.ron"]
+    }
+}
+
+pub struct TerrainPlugin;
+
+impl Plugin for TerrainPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<TerrainRegistry>()
+            .init_asset_loader::<TerrainRegistryLoader>();
+    }
+}